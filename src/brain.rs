@@ -1,121 +1,826 @@
-use std::{collections::VecDeque, fs::read_to_string, path::Path, time::Duration};
-use gcode::Mnemonic;
+use std::{collections::VecDeque, path::Path, time::{Duration, Instant}};
 use log::{info, warn};
-use crate::state::{AppMode, CncEvent, DebounceDiffTracker, DelayUpdates, DiffTracker, Point3, RemoteEvent, TrackCurrentPrevious};
-use tokio::{sync::{broadcast, mpsc::Sender}, task::yield_now};
+use crate::estimate::MachineLimits;
+use crate::firmware::Firmware;
+use crate::job::{Job, JobState, Progress};
+use crate::jog::{DialVelocity, JogAcceleration, JogMode, JogSettings};
+use crate::modal::{self, ModalState};
+use crate::port_io::{PortError, PortMessage, SerialPortInfo};
+use crate::settings;
+use crate::streaming::{strip_comments, FlowControl, LineNumbering};
+use crate::template::Template;
+use crate::state::{AppMode, CncEvent, DebounceDiffTracker, DebounceTracker, DelayUpdates, DiffTracker, EndStopState, EndStops, Point3, RemoteEvent, TrackCurrentPrevious, TravelLimits};
+use tokio::sync::{broadcast, mpsc::Sender};
 
-pub async fn event_brain_loop(mut xbee_events: broadcast::Receiver<RemoteEvent>, xbee_tx: Sender<String>, mut cnc_events: broadcast::Receiver<CncEvent>, cnc_tx: Sender<String>) {
+/// Zeroes any axis of a relative jog that would drive further into a triggered endstop.
+fn block_triggered_endstops(jog: Point3<f32>, endstops: &EndStops) -> Point3<f32> {
+    let triggered = |name: String| endstops.get(&name) == Some(&EndStopState::Triggered);
+    let block = |axis: &str, v: f32| {
+        if (v < 0.0 && triggered(format!("{axis}_min"))) || (v > 0.0 && triggered(format!("{axis}_max"))) {
+            0.0
+        } else {
+            v
+        }
+    };
+    Point3::new(block("x", jog.x), block("y", jog.y), block("z", jog.z))
+}
+
+/// Variables available to the pre and post job templates.
+fn template_variables(job: &Job, wcs: u32, position: Point3<f32>) -> Vec<(&'static str, String)> {
+    vec![
+        ("file", job.path.display().to_string()),
+        ("file_name", job.path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()),
+        ("wcs", format!("G{wcs}")),
+        ("x", position.x.to_string()),
+        ("y", position.y.to_string()),
+        ("z", position.z.to_string()),
+    ]
+}
+
+/// Numbered lines kept for resend requests. Well beyond what the firmware can have in flight.
+const LINE_HISTORY: usize = 64;
+
+/// Lines on their way to the controller and what is holding them back.
+#[derive(Default)]
+struct Stream {
+    buffer: VecDeque<String>,
+    /// lines at the front of `buffer` making up a jog, whose feed rate is not the program's.
+    jog_lines: usize,
+    /// numbered lines being sent again, ahead of anything in `buffer`.
+    resend: VecDeque<String>,
+    /// lines as written to the port, waiting for an ok.
+    processing: VecDeque<String>,
+    /// line a resend request is being replayed from, and how many more requests
+    /// for it the lines that were in flight behind the bad one will bring.
+    replaying: Option<(u32, usize)>,
+    /// oks still to come that follow a resend request rather than acknowledge a line.
+    resend_oks: usize,
+    numbering: Option<LineNumbering>,
+    /// held while the job is paused.
+    paused: bool,
+    /// held after a firmware error until the firmware asks for a resend, the
+    /// operator resumes or the controller restarts.
+    error_hold: bool,
+    /// the controller is in feed hold with moves still queued on it.
+    feed_held: bool,
+    /// an aborted job's clean up waits for the controller to come back from a reset.
+    abort_after_reset: bool,
+}
+
+/// Drops everything queued for a controller that is starting over, such as after
+/// a reset, and starts the line numbers again. It is back in its default modes.
+fn reset_stream(stream: &mut Stream, modal: &mut ModalState) {
+    stream.buffer.clear();
+    stream.jog_lines = 0;
+    stream.resend.clear();
+    stream.processing.clear();
+    stream.replaying = None;
+    stream.resend_oks = 0;
+    stream.paused = false;
+    stream.error_hold = false;
+    stream.feed_held = false;
+    stream.abort_after_reset = false;
+    *modal = ModalState::default();
+    if stream.numbering.is_some() {
+        stream.buffer.push_back("M110 N0".to_owned());
+    }
+}
+
+/// Stops `job` part way, then stops the spindle and lifts the tool clear.
+async fn abort_job(job: &mut Job, stream: &mut Stream, firmware: Firmware, cnc_tx: &Sender<PortMessage>, xbee_tx: &Sender<PortMessage>) -> Result<(), PortError> {
+    job.stop_reading();
+    stream.buffer.clear();
+    stream.jog_lines = 0;
+    stream.resend.clear();
+    stream.paused = false;
+    stream.error_hold = false;
+    match firmware.discard_held_moves().filter(|_| stream.feed_held) {
+        // resuming would finish the held moves first, so throw them away instead.
+        Some(reset) => {
+            cnc_tx.send(reset).await?;
+            stream.abort_after_reset = true;
+            // nothing more is sent until the controller has restarted.
+            stream.paused = true;
+        },
+        None => stream.buffer.extend(Job::abort_commands()),
+    }
+    stream.feed_held = false;
+    job.state = JobState::Aborted;
+    xbee_tx.send(job.status_message().into()).await?;
+    Ok(())
+}
+
+pub struct BrainConfig {
+    /// Name of the machine profile these settings came from.
+    pub profile: Option<String>,
+    pub xbee_port: SerialPortInfo,
+    pub cnc_port: SerialPortInfo,
+    pub firmware: Firmware,
+    /// Send `N` line numbers and `*` checksums, replaying lines the firmware asks for again.
+    pub line_numbers: bool,
+    pub flow_control: FlowControl,
+    pub pre_job: Template,
+    pub post_job: Template,
+    pub jog_acceleration: JogAcceleration,
+    /// Fastest jog in mm/sec.
+    pub max_feed_rate: f32,
+    /// Longest a single continuous jog may take, in seconds. Keeps the machine
+    /// from running on after the dial stops.
+    pub jog_ceiling_time: f32,
+    /// mm per dial detent until the remote picks another.
+    pub jog_step: f32,
+    pub machine: MachineLimits,
+    /// Longest pause between passes of the event loop, cut short by any event.
+    pub loop_interval: Duration,
+    /// Jogs stop at these and jobs with moves outside them are rejected by the pre-flight check.
+    pub travel_limits: Option<TravelLimits>,
+}
+
+pub async fn event_brain_loop(mut xbee_events: broadcast::Receiver<RemoteEvent>, xbee_tx: Sender<PortMessage>, mut cnc_events: broadcast::Receiver<CncEvent>, cnc_tx: Sender<PortMessage>, xbee_port_tx: Sender<SerialPortInfo>, cnc_port_tx: Sender<SerialPortInfo>, mut config: BrainConfig) -> Result<(), PortError> {
+    let mut firmware = config.firmware;
+    let mut flow_control = config.flow_control;
     let mut mode = AppMode::Jog;
-    let mut is_absolute = false;
+    let mut modal = ModalState::default();
+    // limits the last jog was cut short at, so the remote is told once per axis.
+    let mut soft_limits_pinned: Vec<String> = vec![];
     let mut cnc_has_communicted = true;
     let mut dial: DiffTracker<Point3<i64>> = DiffTracker::new(Default::default());
     let mut cnc_position: DebounceDiffTracker<Point3<f32>> = DebounceDiffTracker::new(Point3::<f32>::default(), Duration::from_millis(100));
+    let mut position_poll = DebounceTracker::new(Duration::from_millis(500));
+    let mut status_poll = DebounceTracker::new(Duration::from_millis(200));
+    // jog motion is cancelled once the dial has been still this long.
+    let mut dial_still = DebounceTracker::new(Duration::from_millis(150));
+    let mut jogging = false;
+    let mut jog_settings = JogSettings { step: Point3::new_uniform(config.jog_step), ..JogSettings::default() };
+    let mut dial_velocity = DialVelocity::default();
+    let mut endstops = EndStops::new();
+    // nothing is written while the controller's port is gone, or before it first opens.
+    let mut disconnected = true;
+    let mut job: Option<Job> = None;
+    let mut job_progress: DebounceDiffTracker<Option<Progress>> = DebounceDiffTracker::new(None, Duration::from_secs(1));
     //let mut current_feed_rate = 0;
-    let mut gcode_buffer = VecDeque::<String>::new();
-    let mut gcode_processing = VecDeque::<String>::new();
-    let cnc_buffer_size = 5;
+    let mut stream = Stream { numbering: config.line_numbers.then(|| LineNumbering::new(LINE_HISTORY)), ..Stream::default() };
+    reset_stream(&mut stream, &mut modal);
+
+    // each axis may move as far per jog as its feed allows in the ceiling time.
+    let mut max_jog = config.machine.feed.apply(|feed| feed.min(config.max_feed_rate) * config.jog_ceiling_time);
+    info!("max move per jog: {}", max_jog);
+    info!("flow control: {:?}", flow_control);
 
-    let celing_time = 0.1;
-    let max_feed_rate = 300.0;
-    let scale = (0.1, max_feed_rate*celing_time);
-    info!("max move per jog: {}", scale.1);
+    // received while waiting at the end of the last pass.
+    let mut next_remote_event: Option<RemoteEvent> = None;
+    let mut next_cnc_event: Option<CncEvent> = None;
 
     loop {
         //info!("brain loop.");
-        yield_now().await;
-        if let Ok(x_event) = xbee_events.try_recv() {
+        if let Some(x_event) = next_remote_event.take().or_else(|| xbee_events.try_recv().ok()) {
+            // (path, 1 based line) of a file to run.
+            let mut start_job = None;
             match x_event {
-                RemoteEvent::DialXYZEvent(p) => { *dial.current_mut() = p; },
+                RemoteEvent::DialXYZEvent(p) => {
+                    let turned = p.apply_other(*dial.current(), |p, current| (p - current).abs());
+                    dial_velocity.record(Instant::now(), (turned.x + turned.y + turned.z) as u64);
+                    *dial.current_mut() = p;
+                    dial_still.update();
+                    // turning the dial during a job must not turn into a jump once it ends, nor move
+                    // the machine away from the state a loaded job was checked from before it starts.
+                    if mode != AppMode::Jog || job.is_some() {
+                        dial.update();
+                    }
+                },
                 RemoteEvent::SDList((path, skip)) => {
-                    let path = Path::new(&path);
-                    if path.is_dir() {
-                        let paths = std::fs::read_dir(path).unwrap().enumerate().skip(skip).take(5);
-                        for (i, path) in paths {
-                            if path.is_err() {
-                                continue;
+                    match std::fs::read_dir(&path) {
+                        Ok(entries) => {
+                            for (i, entry) in entries.enumerate().skip(skip).take(5) {
+                                if let Ok(entry) = entry {
+                                    xbee_tx.send(format!("L:{} {}", i, entry.file_name().to_string_lossy()).into()).await?;
+                                }
                             }
-                            xbee_tx.send(format!("L:{} {}", i, path.unwrap().file_name().to_str().unwrap())).await.unwrap();
+                        },
+                        Err(e) => {
+                            warn!("unable to list {}: {}", path, e);
+                            xbee_tx.send(format!("M: cannot list {path}: {e}\n").into()).await?;
+                        },
+                    }
+                },
+                RemoteEvent::SDLoadFile(file_path) => { start_job = Some((file_path, 1)); },
+                RemoteEvent::ResumeFile((file_path, first_line)) => { start_job = Some((file_path, first_line)); },
+                RemoteEvent::RunGCode(gcode) => {
+                    if job.is_some() {
+                        warn!("job running, ignoring gcode {}", gcode);
+                    }
+                    else {
+                        stream.buffer.push_back(gcode);
+                    }
+                },
+                RemoteEvent::JobPause => {
+                    if let Some(job) = job.as_mut().filter(|job| job.state == JobState::Running) {
+                        job.state = JobState::Paused;
+                        stream.paused = true;
+                        if let Some(hold) = firmware.feed_hold() {
+                            cnc_tx.send(hold).await?;
+                            stream.feed_held = true;
                         }
+                        xbee_tx.send(job.status_message().into()).await?;
                     }
                 },
-                RemoteEvent::SDLoadFile(file_path) => {
-                    let p = Path::new(&file_path);
-                    if p.is_file() {
-                        //todo configurable pre-job
-                        gcode_buffer.push_back("G90".to_owned());
-                        for line in read_to_string(p).unwrap().lines() {
-                            gcode_buffer.push_back(line.to_string());
+                RemoteEvent::JobResume => {
+                    stream.paused = false;
+                    stream.error_hold = false;
+                    if let Some(job) = job.as_mut().filter(|job| job.state == JobState::Paused) {
+                        job.state = JobState::Running;
+                        if let Some(start) = firmware.cycle_start() {
+                            cnc_tx.send(start).await?;
                         }
-                        //todo configurable post-job
-                        gcode_buffer.push_back("G90".to_owned());
-                        gcode_buffer.push_back("G21".to_owned());
-                        //gcode_buffer.push_back("M02".to_owned());
+                        stream.feed_held = false;
+                        xbee_tx.send(job.status_message().into()).await?;
+                    }
+                },
+                RemoteEvent::JogSetting(command) => {
+                    jog_settings.apply(command);
+                    xbee_tx.send(format!("{jog_settings}\n").into()).await?;
+                },
+                RemoteEvent::ListProfiles => {
+                    match settings::profiles() {
+                        Ok(profiles) => {
+                            for (i, name) in profiles.iter().enumerate() {
+                                xbee_tx.send(format!("C:{i} {name}\n").into()).await?;
+                            }
+                        },
+                        Err(e) => xbee_tx.send(format!("M: profiles: {e}\n").into()).await?,
+                    }
+                    xbee_tx.send(format!("C:={}\n", config.profile.as_deref().unwrap_or("-")).into()).await?;
+                },
+                RemoteEvent::SelectProfile(_) if job.is_some() => {
+                    xbee_tx.send("M: job running\n".to_owned().into()).await?;
+                },
+                RemoteEvent::SelectProfile(name) => {
+                    match settings::load(Some(&name)).and_then(|(loaded, _)| settings::brain_config(&loaded)) {
+                        Ok(mut next) => {
+                            info!("switching to profile {}", name);
+                            next.profile = Some(name);
+                            if next.xbee_port != config.xbee_port {
+                                xbee_port_tx.send(next.xbee_port.clone()).await?;
+                            }
+                            if next.cnc_port != config.cnc_port {
+                                cnc_port_tx.send(next.cnc_port.clone()).await?;
+                            }
+                            config = next;
+                            firmware = config.firmware;
+                            flow_control = config.flow_control;
+                            max_jog = config.machine.feed.apply(|feed| feed.min(config.max_feed_rate) * config.jog_ceiling_time);
+                            stream.numbering = config.line_numbers.then(|| LineNumbering::new(LINE_HISTORY));
+                            jog_settings.step = Point3::new_uniform(config.jog_step);
+                            jogging = false;
+                            // whatever was queued was meant for the old machine.
+                            reset_stream(&mut stream, &mut modal);
+                            xbee_tx.send(format!("C:={}\n", config.profile.as_deref().unwrap_or("-")).into()).await?;
+                        },
+                        Err(e) => {
+                            warn!("unable to load profile {}: {}", name, e);
+                            xbee_tx.send(format!("M: profile {name}: {e}\n").into()).await?;
+                        },
+                    }
+                },
+                RemoteEvent::JobStart => {
+                    if let Some(mut awaiting) = job.take_if(|job| job.state == JobState::AwaitingConfirmation) {
+                        if !awaiting.preflight_passed() {
+                            xbee_tx.send("M: pre-flight check not finished\n".to_owned().into()).await?;
+                            job = Some(awaiting);
+                        }
+                        else {
+                            match config.pre_job.render(&template_variables(&awaiting, modal.wcs, *cnc_position.current())) {
+                                Ok(pre_job) => {
+                                    stream.buffer.extend(pre_job);
+                                    awaiting.state = JobState::Running;
+                                    info!("job started: {} from line {}", awaiting.path.display(), awaiting.first_line);
+                                    xbee_tx.send(awaiting.status_message().into()).await?;
+                                    job = Some(awaiting);
+                                    mode = AppMode::RunningFile;
+                                },
+                                Err(e) => {
+                                    warn!("unable to load pre-job script, not starting {}: {}", awaiting.path.display(), e);
+                                    awaiting.stop_reading();
+                                    xbee_tx.send(format!("M: pre-job script: {e}\n").into()).await?;
+                                    awaiting.state = JobState::Aborted;
+                                    xbee_tx.send(awaiting.status_message().into()).await?;
+                                },
+                            }
+                        }
+                    }
+                },
+                RemoteEvent::JobAbort if job.as_ref().is_some_and(|job| job.state == JobState::AwaitingConfirmation) => {
+                    // nothing has been sent yet, just drop it.
+                    if let Some(mut cancelled) = job.take() {
+                        info!("job cancelled before starting: {}", cancelled.path.display());
+                        cancelled.stop_reading();
+                        cancelled.state = JobState::Aborted;
+                        xbee_tx.send(cancelled.status_message().into()).await?;
+                    }
+                },
+                RemoteEvent::JobAbort => {
+                    if let Some(job) = job.as_mut().filter(|job| job.state != JobState::Aborted) {
+                        warn!("job aborted: {}", job.path.display());
+                        abort_job(job, &mut stream, firmware, &cnc_tx, &xbee_tx).await?;
                     }
                 },
-                RemoteEvent::RunGCode(gcode) => { gcode_buffer.push_back(gcode); },
             };
+            if let Some((file_path, first_line)) = start_job {
+                let p = Path::new(&file_path);
+                if job.is_some() {
+                    warn!("job already loaded, ignoring {}", file_path);
+                    xbee_tx.send("M: job already running\n".to_owned().into()).await?;
+                }
+                else if p.is_file() {
+                    info!("checking {} from line {}", file_path, first_line);
+                    job = Some(Job::start_from(p.to_path_buf(), first_line, modal.clone(), config.travel_limits, config.machine));
+                }
+                else {
+                    warn!("no such file {}", file_path);
+                    xbee_tx.send(format!("M: no such file {file_path}\n").into()).await?;
+                }
+            }
         }
-        if let Ok(cnc_event) = cnc_events.try_recv() {
+        if let Some(cnc_event) = next_cnc_event.take().or_else(|| cnc_events.try_recv().ok()) {
             if !cnc_has_communicted && mode == AppMode::Uninitialized {
                 cnc_has_communicted = true;
                 mode = AppMode::Jog;
             }
             match cnc_event {
                 CncEvent::Unknown => {},
-                CncEvent::Ok => { gcode_processing.pop_front(); },
-                CncEvent::PositionReport(_) => todo!(),
-                CncEvent::EndStopStates(_) => todo!(),
+                CncEvent::Ok if stream.resend_oks > 0 => { stream.resend_oks -= 1; },
+                CncEvent::Ok => {
+                    let acknowledged = stream.processing.pop_front();
+                    // a line from the replay got through, asking for it again is a new request.
+                    if let Some((from, _)) = stream.replaying {
+                        if acknowledged.as_deref().and_then(LineNumbering::line_number).is_some_and(|n| n >= from) {
+                            stream.replaying = None;
+                        }
+                    }
+                },
+                // the line is still running, it will be acknowledged later.
+                CncEvent::Busy => {},
+                CncEvent::PositionReport(report) => {
+                    modal.set_work_position(report.position);
+                    *cnc_position.current_mut() = report.position;
+                },
+                CncEvent::EndStopStates(states) => {
+                    if states != endstops {
+                        let report: Vec<_> = states.iter().map(|(name, state)| format!("{name}:{state}")).collect();
+                        xbee_tx.send(format!("E: {}\n", report.join(" ")).into()).await?;
+                        endstops = states;
+                    }
+                },
+                CncEvent::Status(status) => {
+                    match (status.machine_position, status.work_position) {
+                        (Some(machine), Some(work)) => modal.set_positions(machine, work),
+                        (Some(machine), None) => modal.machine_position = machine,
+                        (None, Some(work)) => modal.set_work_position(work),
+                        (None, None) => {},
+                    }
+                    *cnc_position.current_mut() = modal.work_position();
+                },
+                CncEvent::Error(e) => {
+                    warn!("cnc error: {}", e);
+                    xbee_tx.send(format!("M: error {e}\n").into()).await?;
+                    // outside a job the line is just rejected, such as a jog past the soft limits.
+                    if let Some(job) = job.as_mut().filter(|job| job.state == JobState::Running) {
+                        stream.paused = true;
+                        stream.error_hold = true;
+                        job.state = JobState::Paused;
+                        xbee_tx.send(job.status_message().into()).await?;
+                    }
+                },
+                CncEvent::Resend(line) => {
+                    // Marlin follows the request with an ok that acknowledges nothing.
+                    stream.resend_oks += 1;
+                    // the error was a transmission error, the resend recovers from it.
+                    if stream.error_hold {
+                        stream.error_hold = false;
+                        stream.paused = false;
+                        if let Some(job) = job.as_mut().filter(|job| job.state == JobState::Paused) {
+                            job.state = JobState::Running;
+                            xbee_tx.send(job.status_message().into()).await?;
+                        }
+                    }
+                    match stream.replaying {
+                        // every line in flight after a bad one is rejected with the same request.
+                        Some((from, repeats)) if from == line && repeats > 0 => {
+                            stream.replaying = Some((from, repeats - 1));
+                        },
+                        _ => {
+                            warn!("cnc resend from line {}", line);
+                            match stream.numbering.as_ref().map(|n| n.replay_from(line)) {
+                                Some(Some(replay)) => {
+                                    let rejected = stream.processing.iter().filter(|l| LineNumbering::line_number(l).is_some_and(|n| n >= line)).count();
+                                    stream.replaying = Some((line, rejected.saturating_sub(1)));
+                                    // the rejected lines are no longer in flight, their replay will be.
+                                    stream.processing.retain(|l| LineNumbering::line_number(l).is_none_or(|n| n < line));
+                                    stream.resend = replay.into();
+                                },
+                                Some(None) => {
+                                    warn!("line {} is no longer in the resend history", line);
+                                    stream.paused = true;
+                                    xbee_tx.send(format!("M: cannot resend line {line}\n").into()).await?;
+                                },
+                                // without numbers there is no telling which were rejected, send them all again.
+                                None => {
+                                    stream.replaying = Some((line, stream.processing.len().saturating_sub(1)));
+                                    stream.resend = std::mem::take(&mut stream.processing);
+                                },
+                            }
+                        },
+                    }
+                },
+                CncEvent::Start if stream.abort_after_reset => {
+                    info!("cnc controller reset to abort the job");
+                    reset_stream(&mut stream, &mut modal);
+                    stream.buffer.extend(Job::abort_commands());
+                },
+                CncEvent::Start => {
+                    warn!("cnc controller restarted, dropping {} queued lines", stream.buffer.len() + stream.processing.len());
+                    reset_stream(&mut stream, &mut modal);
+                    if let Some(mut lost) = job.take() {
+                        lost.state = JobState::Aborted;
+                        xbee_tx.send(lost.status_message().into()).await?;
+                    }
+                    mode = AppMode::Jog;
+                    xbee_tx.send("M: controller reset\n".to_owned().into()).await?;
+                },
+                CncEvent::Disconnected => {
+                    warn!("cnc controller disconnected, dropping {} queued lines", stream.buffer.len() + stream.processing.len());
+                    disconnected = true;
+                    jogging = false;
+                    reset_stream(&mut stream, &mut modal);
+                    if let Some(mut lost) = job.take() {
+                        lost.stop_reading();
+                        lost.state = JobState::Aborted;
+                        xbee_tx.send(lost.status_message().into()).await?;
+                    }
+                    mode = AppMode::Uninitialized;
+                    cnc_has_communicted = false;
+                    xbee_tx.send("M: controller disconnected\n".to_owned().into()).await?;
+                },
+                CncEvent::Connected(path) => {
+                    info!("cnc controller connected on {}", path);
+                    disconnected = false;
+                    // lines queued while the port was being opened never reached it, and the
+                    // controller may have been reset or swapped.
+                    reset_stream(&mut stream, &mut modal);
+                    xbee_tx.send(format!("M: controller connected on {path}\n").into()).await?;
+                },
+                CncEvent::PortError(e) => {
+                    xbee_tx.send(format!("M: {e}\n").into()).await?;
+                },
+                CncEvent::Alarm(code) => {
+                    warn!("cnc alarm: {}", code);
+                    xbee_tx.send(format!("M: ALARM {code}\n").into()).await?;
+                },
+                CncEvent::Message(message) => {
+                    info!("cnc message: {}", message);
+                    xbee_tx.send(format!("M: {message}\n").into()).await?;
+                },
             }
         }
 
-        if mode == AppMode::Jog && gcode_processing.len() < cnc_buffer_size && dial.needs_update() {
-
-            let mut jog = dial.current().to_f32().sub(dial.previous().to_f32());
-            jog = jog.apply(|v| v * scale.0);
-            jog = jog.apply(|v| v.clamp(-scale.1, scale.1));
+        // queue a jog only once the last has gone out, so turning the dial fast can't build up a backlog.
+        if mode == AppMode::Jog && !stream.paused && stream.buffer.is_empty() && flow_control.has_room(&stream.processing) && dial.needs_update() {
 
-            // todo: min step distance to jog.
-            if is_absolute {
-                jog = jog.add(*cnc_position.current());
+            let mut jog = jog_settings.jog(dial.current().apply_other(*dial.previous(), |current, previous| current - previous));
+            // incremental jogs go the whole way, however far the dial turned.
+            if jog_settings.mode == JogMode::Continuous {
+                let multiplier = config.jog_acceleration.multiplier(dial_velocity.velocity(Instant::now()));
+                jog = jog.apply(|v| v * multiplier).apply_other(max_jog, |v, max| v.clamp(-max, max));
             }
+            let allowed = block_triggered_endstops(jog, &endstops);
+            if allowed != jog {
+                warn!("jog {} blocked by triggered endstop", jog);
+            }
+            jog = allowed;
 
-            info!("jog {}", jog);
+            if let Some(limits) = config.travel_limits {
+                let limited = limits.limit_jog(modal.machine_position, jog);
+                let pinned = TravelLimits::pinned(jog, limited);
+                if pinned != soft_limits_pinned {
+                    if !pinned.is_empty() {
+                        warn!("jog {} pinned at soft limit {}", jog, pinned.join(" "));
+                        xbee_tx.send(format!("M: soft limit {}\n", pinned.join(" ")).into()).await?;
+                    }
+                    soft_limits_pinned = pinned;
+                }
+                jog = limited;
+            }
 
-            gcode_buffer.push_back(format!("G0 {jog}"));
+            // todo: min step distance to jog.
+            if jog != Point3::default() {
+                info!("jog {}", jog);
+                let feed_rate = config.machine.feed_along(jog).min(config.max_feed_rate);
+                stream.buffer.extend(firmware.jog_commands(jog, feed_rate * 60.0, &modal));
+                stream.jog_lines = stream.buffer.len();
+                jogging = jog_settings.mode == JogMode::Continuous;
+            }
             dial.update();
         }
 
-        if let Some(code) = gcode_buffer.pop_front() {
-            gcode_processing.push_back(code.clone());
-            // TODO, parse the gcode before send. Update current feed and current
-            // position.
-            cnc_tx.send(code.clone()).await.expect("unable to send gcode.");
-            let code_parts:Vec<_> = gcode::parse(&code).collect();
-            for command in code_parts {
-                match (command.mnemonic(), command.major_number(), command.minor_number()) {
-                    (Mnemonic::General, 91, _) => { is_absolute = true; },
-                    (Mnemonic::General, 90, _) => { is_absolute = false; },
-                    (Mnemonic::General, 0, _)|(Mnemonic::General, 1, _) => {
-                        let cnc_position = cnc_position.current_mut();
-                        let p_default = if is_absolute {
-                            Point3::<f32>::default()
-                        } else {
-                            cnc_position.clone()
-                        };
-                        cnc_position.x = command.value_for('X').unwrap_or(p_default.x);
-                        cnc_position.y = command.value_for('Y').unwrap_or(p_default.y);
-                        cnc_position.z = command.value_for('Z').unwrap_or(p_default.z);
+        if jogging && (mode != AppMode::Jog || dial_still.needs_update()) {
+            jogging = false;
+            if let Some(cancel) = firmware.jog_cancel().filter(|_| mode == AppMode::Jog) {
+                info!("dial stopped, cancelling jog");
+                cnc_tx.send(cancel).await?;
+            }
+        }
+
+        if let Some(current) = job.as_mut().filter(|job| job.state == JobState::AwaitingConfirmation) {
+            match current.poll_preflight() {
+                Some(Ok(preflight)) => {
+                    for message in preflight.messages() {
+                        xbee_tx.send(message.into()).await?;
+                    }
+                    if !preflight.passed() {
+                        warn!("job rejected by pre-flight check: {}", current.path.display());
+                        current.state = JobState::Rejected;
+                    }
+                    xbee_tx.send(current.status_message().into()).await?;
+                },
+                Some(Err(e)) => {
+                    xbee_tx.send(format!("M: {e}\n").into()).await?;
+                    current.state = JobState::Rejected;
+                    xbee_tx.send(current.status_message().into()).await?;
+                },
+                None => {},
+            }
+            if current.state == JobState::Rejected {
+                current.stop_reading();
+                job = None;
+            }
+        }
+        else if let Some(current) = job.as_mut() {
+            match current.fill(&mut stream.buffer) {
+                Ok(true) => match config.post_job.render(&template_variables(current, modal.wcs, *cnc_position.current())) {
+                    Ok(post_job) => stream.buffer.extend(post_job),
+                    Err(e) => {
+                        warn!("unable to load post-job script: {}", e);
+                        xbee_tx.send(format!("M: post-job script: {e}\n").into()).await?;
                     },
-                    _ => { warn!("unknown gcode command: {}", command); },
+                },
+                Ok(false) => {},
+                // only part of the file was read, finishing would leave a half cut part.
+                Err(e) if current.state != JobState::Aborted => {
+                    warn!("job aborted, unable to read {}: {}", current.path.display(), e);
+                    xbee_tx.send(format!("M: unable to read {}: {e}\n", current.path.display()).into()).await?;
+                    abort_job(current, &mut stream, firmware, &cnc_tx, &xbee_tx).await?;
+                },
+                Err(_) => {},
+            }
+            *job_progress.current_mut() = Some(current.progress(stream.buffer.len() + stream.processing.len()));
+            if job_progress.update_check() {
+                if let Some(progress) = job_progress.current() {
+                    xbee_tx.send(format!("{progress}\n").into()).await?;
+                }
+            }
+            let drained = current.is_read() && stream.buffer.is_empty() && stream.resend.is_empty() && stream.processing.is_empty();
+            if drained && current.state != JobState::Paused {
+                if current.state == JobState::Running {
+                    current.state = JobState::Finished;
+                    info!("job finished: {}", current.path.display());
+                    xbee_tx.send(current.status_message().into()).await?;
                 }
+                job = None;
+                mode = AppMode::Jog;
+            }
+        }
+
+        // ask the machine where it actually is.
+        if let Some(query) = firmware.realtime_status_query() {
+            if !disconnected && status_poll.update_check() {
+                cnc_tx.send(PortMessage::Realtime(query)).await?;
+            }
+        }
+        else if !stream.paused && !disconnected && stream.buffer.is_empty() && stream.processing.is_empty() && position_poll.update_check() {
+            stream.buffer.extend(firmware.status_commands().iter().map(|c| c.to_string()));
+        }
+
+        if !stream.paused && !disconnected {
+            // replayed lines were already accounted for when first sent.
+            if let Some(line) = stream.resend.front().filter(|line| flow_control.can_send(&stream.processing, line)).cloned() {
+                stream.resend.pop_front();
+                stream.processing.push_back(line.clone());
+                cnc_tx.send(PortMessage::Line(line)).await?;
+            }
+            else if let Some(code) = stream.buffer.front().filter(|code| stream.resend.is_empty() && flow_control.can_send(&stream.processing, code)).cloned() {
+                stream.buffer.pop_front();
+                let jog_line = stream.jog_lines > 0;
+                stream.jog_lines = stream.jog_lines.saturating_sub(1);
+                let line = match stream.numbering.as_mut() {
+                    // comment only lines would be numbered but never acknowledged.
+                    Some(_) if strip_comments(&code).is_empty() => None,
+                    Some(numbering) => Some(numbering.number(&code)),
+                    None => Some(code.clone()),
+                };
+                if let Some(line) = line {
+                    stream.processing.push_back(line.clone());
+                    cnc_tx.send(PortMessage::Line(line)).await?;
+                }
+                if let Some(jog) = code.strip_prefix("$J=") {
+                    modal.apply_jog(jog);
+                }
+                // other `$` lines are GRBL system commands, not G-code.
+                else if !code.starts_with('$') {
+                    for command in modal.parse_line(&code).iter().filter(|c| !modal::supports(c)) {
+                        warn!("unknown gcode command: {}", command);
+                    }
+                    let feed_rate = modal.feed_rate;
+                    modal.apply(&code);
+                    // a program that never sets a feed must still be caught, jog speed is no cutting speed.
+                    if jog_line {
+                        modal.feed_rate = feed_rate;
+                    }
+                }
+                *cnc_position.current_mut() = modal.work_position();
             }
         }
         if cnc_position.update_check() {
-            let p = cnc_position.current().clone();
-            xbee_tx.send(format!("P: {p}\n")).await.unwrap();
+            let p = *cnc_position.current();
+            xbee_tx.send(format!("P: {p}\n").into()).await?;
         }
 
-        yield_now().await;
-        tokio::time::sleep(Duration::from_millis(20)).await;
+        // an event cuts the wait short, so jogs and acknowledgements are acted on at once.
+        tokio::select! {
+            _ = tokio::time::sleep(config.loop_interval) => {},
+            Ok(event) = xbee_events.recv() => next_remote_event = Some(event),
+            Ok(event) = cnc_events.recv() => next_cnc_event = Some(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{sync::mpsc::{self, Receiver}, time::timeout};
+    use crate::framing::Framing;
+
+    fn port(path: &str, firmware: Option<Firmware>) -> SerialPortInfo {
+        SerialPortInfo { path: path.to_owned(), baud: 115200, firmware, usb_ids: vec![], framing: Framing::default() }
+    }
+
+    fn marlin_config() -> BrainConfig {
+        BrainConfig {
+            profile: None,
+            xbee_port: port("/dev/ttyAMA0", None),
+            cnc_port: port("/dev/ttyUSB0", Some(Firmware::Marlin)),
+            firmware: Firmware::Marlin,
+            line_numbers: true,
+            flow_control: Firmware::Marlin.default_flow_control(),
+            pre_job: Template::default(),
+            post_job: Template::default(),
+            jog_acceleration: JogAcceleration::default(),
+            max_feed_rate: 300.0,
+            jog_ceiling_time: 0.1,
+            jog_step: 0.1,
+            machine: MachineLimits::default(),
+            loop_interval: Duration::from_millis(20),
+            travel_limits: None,
+        }
+    }
+
+    /// The brain running on its own task, with the test playing the remote and the controller.
+    struct Brain {
+        remote: broadcast::Sender<RemoteEvent>,
+        controller: broadcast::Sender<CncEvent>,
+        written: Receiver<PortMessage>,
+        remote_messages: Receiver<PortMessage>,
+    }
+
+    impl Brain {
+        fn start(config: BrainConfig) -> Self {
+            let (remote, remote_events) = broadcast::channel(32);
+            let (controller, cnc_events) = broadcast::channel(32);
+            let (remote_tx, remote_messages) = mpsc::channel(1024);
+            let (cnc_tx, written) = mpsc::channel(32);
+            let (xbee_port_tx, _) = mpsc::channel(2);
+            let (cnc_port_tx, _) = mpsc::channel(2);
+            tokio::spawn(event_brain_loop(remote_events, remote_tx, cnc_events, cnc_tx, xbee_port_tx, cnc_port_tx, config));
+            Self { remote, controller, written, remote_messages }
+        }
+
+        fn remote_sends(&self, event: RemoteEvent) {
+            assert!(self.remote.send(event).is_ok(), "brain stopped");
+        }
+
+        fn controller_sends(&self, event: CncEvent) {
+            assert!(self.controller.send(event).is_ok(), "brain stopped");
+        }
+
+        /// The next line or byte written to the controller, None if the brain has gone quiet.
+        async fn next_written(&mut self) -> Option<PortMessage> {
+            timeout(Duration::from_secs(1), self.written.recv()).await.ok().flatten()
+        }
+
+        /// The next message to the remote starting with `prefix`, None if none comes.
+        async fn remote_message(&mut self, prefix: &str) -> Option<String> {
+            loop {
+                match timeout(Duration::from_secs(1), self.remote_messages.recv()).await {
+                    Ok(Some(PortMessage::Line(line))) if line.starts_with(prefix) => return Some(line),
+                    Ok(Some(_)) => {},
+                    _ => return None,
+                }
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nothing_is_written_until_the_controller_connects() {
+        let mut brain = Brain::start(marlin_config());
+        brain.remote_sends(RemoteEvent::RunGCode("G0 X1".to_owned()));
+        assert_eq!(brain.next_written().await, None);
+
+        brain.controller_sends(CncEvent::Connected("/dev/ttyUSB0".to_owned()));
+        assert_eq!(brain.next_written().await, Some(PortMessage::Line("M110 N0".to_owned())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lines_rejected_after_a_bad_one_are_replayed_once() {
+        let mut brain = Brain::start(marlin_config());
+        brain.controller_sends(CncEvent::Connected("/dev/ttyUSB0".to_owned()));
+        assert_eq!(brain.next_written().await, Some(PortMessage::Line("M110 N0".to_owned())));
+        brain.controller_sends(CncEvent::Ok);
+
+        let mut numbering = LineNumbering::new(LINE_HISTORY);
+        let lines: Vec<_> = (1..=10).map(|i| PortMessage::Line(numbering.number(&format!("G0 X{i}")))).collect();
+        for i in 1..=5 {
+            brain.remote_sends(RemoteEvent::RunGCode(format!("G0 X{i}")));
+        }
+        for line in &lines[..5] {
+            assert_eq!(brain.next_written().await.as_ref(), Some(line));
+        }
+
+        // N2 arrives corrupted and N3 to N5 behind it are each rejected with the same request.
+        brain.controller_sends(CncEvent::Ok);
+        for _ in 2..=5 {
+            brain.controller_sends(CncEvent::Error("checksum mismatch, Last Line: 1".to_owned()));
+            brain.controller_sends(CncEvent::Resend(2));
+            brain.controller_sends(CncEvent::Ok);
+        }
+        for line in &lines[1..5] {
+            assert_eq!(brain.next_written().await.as_ref(), Some(line));
+        }
+        assert_eq!(brain.next_written().await, None);
+
+        // once the replay is acknowledged the whole buffer is free again.
+        for _ in 2..=5 {
+            brain.controller_sends(CncEvent::Ok);
+        }
+        for i in 6..=10 {
+            brain.remote_sends(RemoteEvent::RunGCode(format!("G0 X{i}")));
+        }
+        for line in &lines[5..] {
+            assert_eq!(brain.next_written().await.as_ref(), Some(line));
+        }
+        assert_eq!(brain.next_written().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn marlin_jogs_in_mm_without_taking_over_the_feed() {
+        let mut brain = Brain::start(BrainConfig { line_numbers: false, ..marlin_config() });
+        brain.controller_sends(CncEvent::Connected("/dev/ttyUSB0".to_owned()));
+        assert!(brain.remote_message("M: controller connected").await.is_some());
+        brain.remote_sends(RemoteEvent::RunGCode("G20".to_owned()));
+        assert_eq!(brain.next_written().await, Some(PortMessage::Line("G20".to_owned())));
+        brain.controller_sends(CncEvent::Ok);
+
+        brain.remote_sends(RemoteEvent::DialXYZEvent(Point3::new(10, 0, 0)));
+        let mut jog = vec![];
+        while let Some(PortMessage::Line(line)) = brain.next_written().await {
+            jog.push(line);
+            brain.controller_sends(CncEvent::Ok);
+        }
+        assert!(matches!(jog.as_slice(), [g91, g21, jog, g20, g90] if g91 == "G91" && g21 == "G21" && jog.starts_with("G0 X") && g20 == "G20" && g90 == "G90"), "{jog:?}");
+
+        // the jog feed would otherwise cut this file's first move at jog speed.
+        let path = std::env::temp_dir().join(format!("rpi_cnc_remote_jog_feed_{}.nc", std::process::id()));
+        std::fs::write(&path, "G1 X1 Y1\n").expect("write test file");
+        brain.remote_sends(RemoteEvent::SDLoadFile(path.display().to_string()));
+        assert_eq!(brain.remote_message("J:").await.as_deref(), Some("J:rejected\n"));
+        std::fs::remove_file(path).expect("remove test file");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_dial_is_ignored_while_a_job_awaits_confirmation() {
+        let mut brain = Brain::start(BrainConfig { line_numbers: false, ..marlin_config() });
+        brain.controller_sends(CncEvent::Connected("/dev/ttyUSB0".to_owned()));
+        assert!(brain.remote_message("M: controller connected").await.is_some());
+
+        let path = std::env::temp_dir().join(format!("rpi_cnc_remote_confirm_jog_{}.nc", std::process::id()));
+        std::fs::write(&path, "G21 G90\nG1 X1 F100\n").expect("write test file");
+        brain.remote_sends(RemoteEvent::SDLoadFile(path.display().to_string()));
+        assert_eq!(brain.remote_message("J:").await.as_deref(), Some("J:confirm\n"));
+
+        // the pre-flight ran from where the machine was at load time.
+        brain.remote_sends(RemoteEvent::DialXYZEvent(Point3::new(10, 0, 0)));
+        assert_eq!(brain.next_written().await, None);
+
+        brain.remote_sends(RemoteEvent::JobStart);
+        assert_eq!(brain.next_written().await, Some(PortMessage::Line("G21 G90".to_owned())));
+        std::fs::remove_file(path).expect("remove test file");
     }
 }
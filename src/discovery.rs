@@ -0,0 +1,179 @@
+use std::{fmt::{self, Display}, io, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use log::{info, warn};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, time::{sleep, timeout, Instant}};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::{firmware::Firmware, port_io::SerialPortInfo};
+
+/// `CNC_PORT` value asking for the controller to be found on USB.
+pub const AUTO_PORT: &str = "auto";
+
+/// How long a port gets to identify itself. Boards that reset when the port
+/// opens spend a second or two in their bootloader first.
+const PROBE_TIME: Duration = Duration::from_secs(4);
+const PROBE_RESEND: Duration = Duration::from_secs(1);
+/// How long the controller has to go quiet after the probe, so the rest of its
+/// answer is not taken for replies to what is sent next.
+const PROBE_QUIET: Duration = Duration::from_millis(300);
+
+/// A USB vendor id, and product id when only one of the vendor's devices is wanted.
+/// Written `1a86:7523` or `2341` in `CNC_USB_IDS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vendor: u16,
+    pub product: Option<u16>,
+}
+
+impl UsbId {
+    const fn new(vendor: u16, product: Option<u16>) -> Self {
+        Self { vendor, product }
+    }
+
+    pub fn matches(&self, vendor: u16, product: u16) -> bool {
+        self.vendor == vendor && self.product.is_none_or(|p| p == product)
+    }
+}
+
+impl Display for UsbId {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.product {
+            Some(product) => write!(fmt, "{:04x}:{:04x}", self.vendor, product),
+            None => write!(fmt, "{:04x}", self.vendor),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseUsbIdError;
+impl FromStr for UsbId {
+    type Err = ParseUsbIdError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let hex = |s: &str| u16::from_str_radix(s.trim(), 16).map_err(|_| ParseUsbIdError);
+        match input.split_once(':') {
+            Some((vendor, product)) => Ok(UsbId::new(hex(vendor)?, Some(hex(product)?))),
+            None => Ok(UsbId::new(hex(input)?, None)),
+        }
+    }
+}
+
+/// USB serial chips and boards controllers are usually attached with.
+const KNOWN_IDS: [UsbId; 8] = [
+    UsbId::new(0x1a86, Some(0x7523)), // CH340
+    UsbId::new(0x1a86, Some(0x5523)), // CH341
+    UsbId::new(0x0403, Some(0x6001)), // FTDI FT232R
+    UsbId::new(0x0403, Some(0x6015)), // FTDI FT231X
+    UsbId::new(0x2341, None),         // Arduino
+    UsbId::new(0x2a03, None),         // Arduino (arduino.org)
+    UsbId::new(0x0483, Some(0x5740)), // STM32 virtual COM port
+    UsbId::new(0x1d50, Some(0x6029)), // Marlin on LPC176x boards
+];
+
+/// `/dev` nodes of the serial ports the USB device at `sysfs` provides. usb-serial
+/// chips put `ttyUSB*` right under the interface, CDC ACM puts `ttyACM*` under its `tty` directory.
+fn tty_nodes(sysfs: &Path) -> Vec<PathBuf> {
+    let entries = |dir: &Path| std::fs::read_dir(dir).into_iter().flatten().flatten().map(|e| e.path()).collect::<Vec<_>>();
+    let name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let mut nodes: Vec<_> = entries(sysfs).iter()
+        // interfaces are named `<device>:<config>.<interface>`.
+        .filter(|interface| name(interface).contains(':'))
+        .flat_map(|interface| [entries(interface), entries(&interface.join("tty"))].concat())
+        .map(|path| name(&path))
+        .filter(|name| name.starts_with("ttyUSB") || name.starts_with("ttyACM"))
+        .map(|name| Path::new("/dev").join(name))
+        .collect();
+    nodes.sort();
+    nodes
+}
+
+/// Serial ports of attached USB devices that are known controller chips or in `extra_ids`.
+pub fn candidates(extra_ids: &[UsbId]) -> Vec<PathBuf> {
+    let devices = match nusb::list_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("unable to list USB devices: {}", e);
+            return vec![];
+        },
+    };
+    devices
+        .filter(|dev| KNOWN_IDS.iter().chain(extra_ids).any(|id| id.matches(dev.vendor_id(), dev.product_id())))
+        .flat_map(|dev| {
+            info!("usb controller candidate {:04x}:{:04x} {}", dev.vendor_id(), dev.product_id(), dev.product_string().unwrap_or("?"));
+            tty_nodes(dev.sysfs_path())
+        })
+        .collect()
+}
+
+/// Opens `port` and finds out whether the controller on it answers as `firmware`
+/// does. The port is handed back open either way, opening it again would reset
+/// boards that reset on DTR.
+pub async fn probe(port: &SerialPortInfo, firmware: Firmware) -> io::Result<(SerialStream, bool)> {
+    let mut serial = tokio_serial::new(port.path.clone(), port.baud).open_native_async()?;
+    let deadline = Instant::now() + PROBE_TIME;
+    let mut received: Vec<u8> = vec![];
+    let mut buf = [0u8; 256];
+    let mut identified = false;
+    'probe: while Instant::now() < deadline {
+        // anything sent while the board is still in its bootloader is lost, so ask again.
+        serial.write_all(format!("{}\n", firmware.probe_command()).as_bytes()).await?;
+        let resend_at = (Instant::now() + PROBE_RESEND).min(deadline);
+        while let Ok(read) = timeout(resend_at.saturating_duration_since(Instant::now()), serial.read(&mut buf)).await {
+            let read = read?;
+            if read == 0 {
+                sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+            received.extend_from_slice(&buf[..read]);
+            while let Some(nl) = received.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = received.drain(..=nl).collect();
+                if firmware.identifies(String::from_utf8_lossy(&line).trim()) {
+                    identified = true;
+                    break 'probe;
+                }
+            }
+        }
+    }
+    // the rest of the answer, and the ok after it, belong to the probe.
+    while let Ok(read) = timeout(PROBE_QUIET, serial.read(&mut buf)).await {
+        if read? == 0 {
+            break;
+        }
+    }
+    Ok((serial, identified))
+}
+
+/// Opens the first USB port found with a controller speaking the firmware of
+/// `configured` on it, for a port that is `auto`.
+pub async fn find(configured: &SerialPortInfo) -> Option<(SerialPortInfo, SerialStream)> {
+    let firmware = configured.firmware?;
+    for path in candidates(&configured.usb_ids) {
+        let port = SerialPortInfo { path: path.to_string_lossy().to_string(), ..configured.clone() };
+        info!("probing {} for {:?}", port.path, firmware);
+        match probe(&port, firmware).await {
+            Ok((serial, true)) => {
+                info!("found {:?} controller on {}", firmware, port.path);
+                return Some((port, serial));
+            },
+            Ok((_, false)) => {},
+            Err(e) => warn!("unable to probe {}: {}", port.path, e),
+        }
+    }
+    warn!("no {:?} controller found on USB", firmware);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_ids() {
+        let ch340: UsbId = "1a86:7523".parse().expect("parse success");
+        assert!(ch340.matches(0x1a86, 0x7523));
+        assert!(!ch340.matches(0x1a86, 0x5523));
+        let arduino: UsbId = "2341".parse().expect("parse success");
+        assert!(arduino.matches(0x2341, 0x0043));
+        assert_eq!(arduino.to_string(), "2341");
+        assert!("xyz:1".parse::<UsbId>().is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{modal::{ModalState, Move, MoveKind}, state::Point3};
+
+/// How fast each axis can go, from `CNC_AXIS_FEED` and `CNC_AXIS_ACCELERATION`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MachineLimits {
+    /// mm/sec, also the speed of G0 and of feed moves before any F word.
+    pub feed: Point3<f32>,
+    /// mm/sec², infinite when unknown.
+    pub acceleration: Point3<f32>,
+}
+
+impl Default for MachineLimits {
+    fn default() -> Self {
+        Self { feed: Point3::new_uniform(300.0), acceleration: Point3::new_uniform(f32::INFINITY) }
+    }
+}
+
+impl MachineLimits {
+    /// The most of `per_axis` a move along `delta` gets, with every axis within its own limit.
+    fn along(per_axis: Point3<f32>, delta: Point3<f32>) -> f32 {
+        let length = delta.mul(delta).sum().sqrt();
+        [(per_axis.x, delta.x), (per_axis.y, delta.y), (per_axis.z, delta.z)].iter()
+            .filter(|(_, d)| *d != 0.0 || length == 0.0)
+            .map(|(limit, d)| if *d == 0.0 { *limit } else { limit * length / d.abs() })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    /// Fastest speed in mm/sec along `delta`.
+    pub fn feed_along(&self, delta: Point3<f32>) -> f32 {
+        Self::along(self.feed, delta)
+    }
+
+    /// Seconds `m` takes, accelerating up to speed and back down again.
+    pub fn move_time(&self, m: &Move) -> f32 {
+        let length = m.length();
+        if length == 0.0 {
+            return 0.0;
+        }
+        // arcs change direction as they go, so take their end to end direction.
+        let delta = m.to.sub(m.from);
+        let top_speed = self.feed_along(delta);
+        let speed = match m.kind {
+            MoveKind::Rapid => top_speed,
+            _ => m.feed_rate.map_or(top_speed, |f| (f / 60.0).min(top_speed)),
+        };
+        let acceleration = Self::along(self.acceleration, delta);
+        if length >= speed * speed / acceleration {
+            length / speed + speed / acceleration
+        } else {
+            // never reaches full speed.
+            2.0 * (length / acceleration).sqrt()
+        }
+    }
+}
+
+/// Estimates how long lines take to run from move length, feed rate and acceleration.
+#[derive(Default)]
+pub struct MoveEstimator {
+    modal: ModalState,
+    machine: MachineLimits,
+}
+
+impl MoveEstimator {
+    pub fn new(machine: MachineLimits) -> Self {
+        Self { modal: ModalState::default(), machine }
+    }
+
+    /// Seconds `line` is expected to take.
+    pub fn line_time(&mut self, line: &str) -> f32 {
+        self.modal.apply(line).iter().map(|m| self.machine.move_time(m)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_and_rapid_moves() {
+        let mut estimator = MoveEstimator::default();
+        assert_eq!(estimator.line_time("G0 X300"), 1.0);
+        assert_eq!(estimator.line_time("G1 X0 F600"), 30.0);
+        assert_eq!(estimator.line_time("G91"), 0.0);
+        assert_eq!(estimator.line_time("G1 Y3 Z4"), 0.5);
+        assert_eq!(estimator.line_time("Y-3 Z-4"), 0.5);
+        assert_eq!(estimator.line_time("F300"), 0.0);
+        assert_eq!(estimator.line_time("X5 ; modal feed"), 1.0);
+        estimator.line_time("G90 G0 X10 Y0 Z0");
+        // half circle of radius 10 at 10 mm/sec.
+        assert!((estimator.line_time("G3 X-10 Y0 I-10 F600") - std::f32::consts::PI).abs() < 1e-4);
+    }
+
+    #[test]
+    fn axis_limits_and_acceleration() {
+        let machine = MachineLimits { feed: Point3::new(100.0, 100.0, 10.0), acceleration: Point3::new(1000.0, 1000.0, 100.0) };
+        assert_eq!(machine.feed_along(Point3::new(1.0, 0.0, 0.0)), 100.0);
+        // Z holds back a move that is mostly along it.
+        assert_eq!(machine.feed_along(Point3::new(0.0, 3.0, 4.0)), 12.5);
+
+        let mut estimator = MoveEstimator::new(machine);
+        // 0.1 sec to reach 100 mm/sec and 0.1 sec to stop, covering 10 mm.
+        assert!((estimator.line_time("G0 X100") - 1.1).abs() < 1e-6);
+        // too short to reach full speed.
+        assert!((estimator.line_time("G0 X99") - 0.0632).abs() < 1e-4);
+    }
+}
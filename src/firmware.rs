@@ -0,0 +1,218 @@
+mod grbl;
+mod marlin;
+
+use std::str::FromStr;
+
+use grbl::GrblParser;
+use marlin::MarlinParser;
+
+use crate::{modal::{DistanceMode, ModalState, Units}, port_io::{LineDecoder, PortError, PortMessage, SerialPortInfo}, state::{CncEvent, Point3}, streaming::FlowControl};
+
+/// The dialect spoken by the attached CNC controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    Marlin,
+    Grbl,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseFirmwareError;
+impl FromStr for Firmware {
+    type Err = ParseFirmwareError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "marlin" => Ok(Firmware::Marlin),
+            "grbl" => Ok(Firmware::Grbl),
+            _ => Err(ParseFirmwareError),
+        }
+    }
+}
+
+/// Decodes with the parser for the firmware of the port last opened.
+pub struct FirmwareDecoder(Box<dyn LineDecoder<CncEvent>>);
+
+impl LineDecoder<CncEvent> for FirmwareDecoder {
+    fn decode_line(&mut self, line: &str) -> Vec<CncEvent> {
+        self.0.decode_line(line)
+    }
+
+    fn port_opened(&mut self, port: &SerialPortInfo) -> Vec<CncEvent> {
+        if let Some(firmware) = port.firmware {
+            *self = firmware.decoder();
+        }
+        let mut events = self.0.port_opened(port);
+        events.push(CncEvent::Connected(port.path.clone()));
+        events
+    }
+
+    fn port_closed(&mut self, port: &SerialPortInfo) -> Vec<CncEvent> {
+        let mut events = self.0.port_closed(port);
+        events.push(CncEvent::Disconnected);
+        events
+    }
+
+    fn port_error(&mut self, port: &SerialPortInfo, error: &PortError) -> Vec<CncEvent> {
+        let mut events = self.0.port_error(port, error);
+        events.push(CncEvent::PortError(error.clone()));
+        events
+    }
+}
+
+impl Firmware {
+    pub fn decoder(&self) -> FirmwareDecoder {
+        match self {
+            Firmware::Marlin => FirmwareDecoder(Box::new(MarlinParser::default())),
+            Firmware::Grbl => FirmwareDecoder(Box::new(GrblParser::default())),
+        }
+    }
+
+    /// Flow control when none is configured. Marlin queues a handful of commands,
+    /// GRBL has a 128 byte serial RX buffer.
+    pub fn default_flow_control(&self) -> FlowControl {
+        match self {
+            Firmware::Marlin => self.sized_flow_control(FlowControl::LineCounting(0)),
+            Firmware::Grbl => self.sized_flow_control(FlowControl::CharacterCounting(0)),
+        }
+    }
+
+    /// `flow` with the size that suits it on this firmware. Both have a 128 byte
+    /// RX buffer by default. GRBL counting lines falls back to send and wait for ok.
+    pub fn sized_flow_control(&self, flow: FlowControl) -> FlowControl {
+        match (self, flow) {
+            (Firmware::Marlin, FlowControl::LineCounting(_)) => FlowControl::LineCounting(5),
+            (Firmware::Grbl, FlowControl::LineCounting(_)) => FlowControl::LineCounting(1),
+            (_, FlowControl::CharacterCounting(_)) => FlowControl::CharacterCounting(128),
+        }
+    }
+
+    /// Whether the firmware checks `N` line numbers and `*` checksums.
+    pub fn supports_line_numbers(&self) -> bool {
+        match self {
+            Firmware::Marlin => true,
+            Firmware::Grbl => false,
+        }
+    }
+
+    /// Stops motion right away. Marlin has no real-time hold, so pausing there only
+    /// stops streaming and lets the moves already queued on the controller finish.
+    pub fn feed_hold(&self) -> Option<PortMessage> {
+        match self {
+            Firmware::Marlin => None,
+            Firmware::Grbl => Some(PortMessage::Realtime(b'!')),
+        }
+    }
+
+    /// Continues motion after `feed_hold`.
+    pub fn cycle_start(&self) -> Option<PortMessage> {
+        match self {
+            Firmware::Marlin => None,
+            Firmware::Grbl => Some(PortMessage::Realtime(b'~')),
+        }
+    }
+
+    /// Throws away the moves queued on a controller in `feed_hold`. A GRBL soft
+    /// reset while stopped in a hold keeps the machine position.
+    pub fn discard_held_moves(&self) -> Option<PortMessage> {
+        match self {
+            Firmware::Marlin => None,
+            Firmware::Grbl => Some(PortMessage::Realtime(0x18)),
+        }
+    }
+
+    /// Status request written outside the line queue. It is never acknowledged with ok.
+    pub fn realtime_status_query(&self) -> Option<u8> {
+        match self {
+            Firmware::Marlin => None,
+            Firmware::Grbl => Some(b'?'),
+        }
+    }
+
+    /// Status commands sent through the line queue while idle. Each is acknowledged with ok.
+    pub fn status_commands(&self) -> &'static [&'static str] {
+        match self {
+            Firmware::Marlin => &["M114", "M119"],
+            Firmware::Grbl => &[],
+        }
+    }
+
+    /// Asks the controller to say what it is.
+    pub fn probe_command(&self) -> &'static str {
+        match self {
+            Firmware::Marlin => "M115",
+            Firmware::Grbl => "$I",
+        }
+    }
+
+    /// Whether `line` shows the controller runs this firmware, from the answer to
+    /// `probe_command` or GRBL's startup banner.
+    pub fn identifies(&self, line: &str) -> bool {
+        match self {
+            Firmware::Marlin => line.contains("FIRMWARE_NAME:Marlin"),
+            Firmware::Grbl => line.starts_with("Grbl ") || line.starts_with("[VER:"),
+        }
+    }
+
+    /// Lines for a relative jog of `jog` mm at `feed_rate` mm/min on a controller
+    /// in `modal`. GRBL's `$J=` leaves the modal state alone and can be cancelled.
+    /// Marlin gets a plain relative move in mm, then its units, distance mode and
+    /// feed rate are put back.
+    pub fn jog_commands(&self, jog: Point3<f32>, feed_rate: f32, modal: &ModalState) -> Vec<String> {
+        if *self == Firmware::Grbl {
+            return vec![format!("$J=G91 G21 {jog} F{feed_rate}")];
+        }
+        let mut lines = vec!["G91".to_owned(), "G21".to_owned(), format!("G0 {jog} F{feed_rate}")];
+        if modal.units == Units::Inches {
+            lines.push("G20".to_owned());
+        }
+        if modal.distance == DistanceMode::Absolute {
+            lines.push("G90".to_owned());
+        }
+        if let Some(feed) = modal.feed_rate {
+            lines.push(format!("G1 F{feed}"));
+        }
+        lines
+    }
+
+    /// Stops jog motion and throws away queued jogs, real-time on GRBL. Marlin's
+    /// M410 quickstop would stop without slowing down and lose steps, so there jogs
+    /// are kept short and left to finish.
+    pub fn jog_cancel(&self) -> Option<PortMessage> {
+        match self {
+            Firmware::Marlin => None,
+            Firmware::Grbl => Some(PortMessage::Realtime(0x85)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::Framing;
+
+    #[test]
+    fn jog_commands() {
+        let jog = Point3::new(1.0, -0.5, 0.0);
+        let mut modal = ModalState::default();
+        assert_eq!(Firmware::Grbl.jog_commands(jog, 1000.0, &modal), ["$J=G91 G21 X1 Y-0.5 Z0 F1000"]);
+        assert_eq!(Firmware::Marlin.jog_commands(jog, 1000.0, &modal), ["G91", "G21", "G0 X1 Y-0.5 Z0 F1000", "G90"]);
+        modal.apply("G20 G91 G1 F4");
+        assert_eq!(Firmware::Marlin.jog_commands(jog, 1000.0, &modal), ["G91", "G21", "G0 X1 Y-0.5 Z0 F1000", "G20", "G1 F4"]);
+    }
+
+    #[test]
+    fn decoder_follows_the_port() {
+        let port = SerialPortInfo { path: "/dev/ttyACM0".to_owned(), baud: 115200, firmware: Some(Firmware::Grbl), usb_ids: vec![], framing: Framing::default() };
+        let mut decoder = Firmware::Marlin.decoder();
+        assert!(matches!(decoder.port_opened(&port).as_slice(), [CncEvent::Connected(path)] if path == "/dev/ttyACM0"));
+        assert!(matches!(decoder.decode_line("<Idle|MPos:1.000,2.000,3.000|FS:0,0>").as_slice(), [CncEvent::Status(_)]));
+        assert!(matches!(decoder.port_closed(&port).as_slice(), [CncEvent::Disconnected]));
+    }
+
+    #[test]
+    fn identifies_controllers() {
+        assert!(Firmware::Marlin.identifies("FIRMWARE_NAME:Marlin bugfix-2.1.x (Jan 1 2024) SOURCE_CODE_URL:github.com/MarlinFirmware/Marlin"));
+        assert!(Firmware::Grbl.identifies("Grbl 1.1h ['$' for help]"));
+        assert!(Firmware::Grbl.identifies("[VER:1.1h.20190825:]"));
+        assert!(!Firmware::Grbl.identifies("FIRMWARE_NAME:Marlin"));
+    }
+}
@@ -0,0 +1,150 @@
+use log::warn;
+
+use crate::{port_io::LineDecoder, state::{CncEvent, MachineState, MachineStatus, Point3}};
+
+/// Parses responses from a GRBL 1.1 controller. GRBL only sends the work
+/// coordinate offset (`WCO:`) every few status reports, so the last one seen is
+/// kept to fill in whichever of MPos/WPos the report left out.
+#[derive(Default)]
+pub struct GrblParser {
+    work_offset: Option<Point3<f32>>,
+}
+
+/// Reads the first three axes of a `1.000,2.000,3.000` field.
+fn parse_xyz(input: &str) -> Option<Point3<f32>> {
+    let mut values = input.split(',').map(|v| v.trim().parse::<f32>());
+    let x = values.next()?.ok()?;
+    let y = values.next()?.ok()?;
+    let z = values.next()?.ok()?;
+    Some(Point3::new(x, y, z))
+}
+
+fn parse_state(input: &str) -> MachineState {
+    // substates follow a colon, e.g. `Hold:0` or `Door:1`.
+    match input.split(':').next().unwrap_or_default() {
+        "Idle" => MachineState::Idle,
+        "Run" => MachineState::Run,
+        "Hold" => MachineState::Hold,
+        "Jog" => MachineState::Jog,
+        "Alarm" => MachineState::Alarm,
+        "Door" => MachineState::Door,
+        "Check" => MachineState::Check,
+        "Home" => MachineState::Home,
+        "Sleep" => MachineState::Sleep,
+        _ => MachineState::Unknown,
+    }
+}
+
+impl GrblParser {
+    fn status(&mut self, report: &str) -> MachineStatus {
+        let mut fields = report.split('|');
+        let mut status = MachineStatus {
+            state: parse_state(fields.next().unwrap_or_default()),
+            machine_position: None,
+            work_position: None,
+            feed_rate: None,
+            spindle_speed: None,
+        };
+        for field in fields {
+            let Some((name, value)) = field.split_once(':') else { continue; };
+            match name {
+                "MPos" => status.machine_position = parse_xyz(value),
+                "WPos" => status.work_position = parse_xyz(value),
+                "WCO" => self.work_offset = parse_xyz(value).or(self.work_offset),
+                "F" => status.feed_rate = value.parse().ok(),
+                "FS" => {
+                    let mut fs = value.split(',').map(|v| v.parse().ok());
+                    status.feed_rate = fs.next().flatten();
+                    status.spindle_speed = fs.next().flatten();
+                },
+                _ => {},
+            }
+        }
+        if let Some(offset) = self.work_offset {
+            match (status.machine_position, status.work_position) {
+                (Some(m), None) => status.work_position = Some(m.sub(offset)),
+                (None, Some(w)) => status.machine_position = Some(w.add(offset)),
+                _ => {},
+            }
+        }
+        status
+    }
+}
+
+impl LineDecoder<CncEvent> for GrblParser {
+    fn decode_line(&mut self, line: &str) -> Vec<CncEvent> {
+        let line = line.trim();
+        if line == "ok" {
+            vec![CncEvent::Ok]
+        }
+        else if let Some(code) = line.strip_prefix("error:") {
+            // an error takes the place of the ok for that line.
+            vec![CncEvent::Error(code.to_owned()), CncEvent::Ok]
+        }
+        else if let Some(code) = line.strip_prefix("ALARM:") {
+            vec![CncEvent::Alarm(code.parse().unwrap_or_default())]
+        }
+        else if let Some(report) = line.strip_prefix('<').and_then(|l| l.strip_suffix('>')) {
+            vec![CncEvent::Status(self.status(report))]
+        }
+        else if line.starts_with("Grbl ") {
+            // `Grbl 1.1h ['$' for help]` is printed after every reset.
+            vec![CncEvent::Start]
+        }
+        else if let Some(message) = line.strip_prefix("[MSG:").and_then(|l| l.strip_suffix(']')) {
+            vec![CncEvent::Message(message.to_owned())]
+        }
+        else {
+            if !line.is_empty() {
+                warn!("unrecognized input: {}", line);
+            }
+            vec![CncEvent::Unknown]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_with_work_offset() {
+        let mut parser = GrblParser::default();
+        let events = parser.decode_line("<Idle|MPos:10.000,20.000,-5.000|FS:500,12000|WCO:1.000,2.000,-10.000>");
+        if let [CncEvent::Status(status)] = &events[..] {
+            assert_eq!(status.state, MachineState::Idle);
+            assert_eq!(status.machine_position, Some(Point3::new(10.0, 20.0, -5.0)));
+            assert_eq!(status.work_position, Some(Point3::new(9.0, 18.0, 5.0)));
+            assert_eq!(status.feed_rate, Some(500.0));
+            assert_eq!(status.spindle_speed, Some(12000.0));
+        }
+        else {
+            panic!("bad event parsed.")
+        }
+
+        // later reports leave WCO out; the last offset still applies.
+        let events = parser.decode_line("<Hold:0|MPos:0.000,0.000,0.000|FS:0,0>");
+        if let [CncEvent::Status(status)] = &events[..] {
+            assert_eq!(status.state, MachineState::Hold);
+            assert_eq!(status.work_position, Some(Point3::new(-1.0, -2.0, 10.0)));
+        }
+        else {
+            panic!("bad event parsed.")
+        }
+    }
+
+    #[test]
+    fn error_acknowledges_line() {
+        let events = GrblParser::default().decode_line("error:20");
+        assert!(matches!(&events[..], [CncEvent::Error(code), CncEvent::Ok] if code == "20"));
+    }
+
+    #[test]
+    fn alarm_and_message() {
+        let mut parser = GrblParser::default();
+        assert!(matches!(parser.decode_line("ALARM:1")[..], [CncEvent::Alarm(1)]));
+        assert!(matches!(parser.decode_line("Grbl 1.1h ['$' for help]")[..], [CncEvent::Start]));
+        let events = parser.decode_line("[MSG:Reset to continue]");
+        assert!(matches!(&events[..], [CncEvent::Message(m)] if m == "Reset to continue"));
+    }
+}
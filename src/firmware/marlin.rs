@@ -0,0 +1,148 @@
+use log::warn;
+
+use crate::{port_io::LineDecoder, state::{CncEvent, EndStopState, EndStops}};
+
+/// Parses responses from a Marlin controller. M119 reports one switch per line,
+/// so those lines are collected and sent as a single event once the block ends.
+#[derive(Default)]
+pub struct MarlinParser {
+    endstops: Option<EndStops>,
+}
+
+impl MarlinParser {
+    fn endstop_line(line: &str) -> Option<(&str, EndStopState)> {
+        let (name, state) = line.split_once(':')?;
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        Some((name, state.parse().ok()?))
+    }
+
+    fn event(line: &str) -> CncEvent {
+        let lower = line.to_lowercase();
+        if let Ok(report) = line.parse() {
+            CncEvent::PositionReport(report)
+        }
+        // `ok`, `ok N12` or `ok T:20.0 /0.0` with temperatures.
+        else if lower == "ok" || lower.starts_with("ok ") {
+            CncEvent::Ok
+        }
+        else if let Some(error) = line.strip_prefix("Error:") {
+            CncEvent::Error(error.trim().to_owned())
+        }
+        else if let Some(resend) = line.strip_prefix("Resend:").or_else(|| line.strip_prefix("rs ")) {
+            match resend.trim().trim_start_matches('N').parse() {
+                Ok(n) => CncEvent::Resend(n),
+                Err(_) => {
+                    warn!("bad resend request: {}", line);
+                    CncEvent::Unknown
+                },
+            }
+        }
+        else if lower.contains("busy:") {
+            CncEvent::Busy
+        }
+        else if lower == "start" {
+            CncEvent::Start
+        }
+        else if let Some(message) = line.strip_prefix("echo:") {
+            CncEvent::Message(message.trim().to_owned())
+        }
+        else {
+            warn!("unrecognized input: {}", line);
+            CncEvent::Unknown
+        }
+    }
+}
+
+impl LineDecoder<CncEvent> for MarlinParser {
+    fn decode_line(&mut self, line: &str) -> Vec<CncEvent> {
+        let line = line.trim();
+        if line == "Reporting endstop status" {
+            self.endstops = Some(EndStops::new());
+            return vec![];
+        }
+        if let Some((name, state)) = Self::endstop_line(line) {
+            self.endstops.get_or_insert_with(EndStops::new).insert(name.to_owned(), state);
+            return vec![];
+        }
+
+        let mut events = vec![];
+        if let Some(endstops) = self.endstops.take() {
+            events.push(CncEvent::EndStopStates(endstops));
+        }
+        events.push(Self::event(line));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(parser: &mut MarlinParser, lines: &[&str]) -> Vec<CncEvent> {
+        lines.iter().flat_map(|line| parser.decode_line(line)).collect()
+    }
+
+    #[test]
+    fn m119_block_is_one_event() {
+        let mut parser = MarlinParser::default();
+        let events = decode_all(&mut parser, &[
+            "Reporting endstop status",
+            "x_min: open",
+            "y_min: open",
+            "z_min: TRIGGERED",
+            "z_probe: open",
+            "ok",
+        ]);
+        assert_eq!(events.len(), 2);
+        if let CncEvent::EndStopStates(endstops) = &events[0] {
+            assert_eq!(endstops.len(), 4);
+            assert_eq!(endstops["x_min"], EndStopState::Open);
+            assert_eq!(endstops["z_min"], EndStopState::Triggered);
+        }
+        else {
+            panic!("bad event parsed.")
+        }
+        assert!(matches!(events[1], CncEvent::Ok));
+    }
+
+    #[test]
+    fn m119_block_without_header() {
+        let mut parser = MarlinParser::default();
+        let events = decode_all(&mut parser, &["x_max: TRIGGERED\r", "ok\r"]);
+        assert_eq!(events.len(), 2);
+        if let CncEvent::EndStopStates(endstops) = &events[0] {
+            assert_eq!(endstops["x_max"], EndStopState::Triggered);
+        }
+        else {
+            panic!("bad event parsed.")
+        }
+    }
+
+    #[test]
+    fn error_is_not_ok() {
+        let mut parser = MarlinParser::default();
+        let events = decode_all(&mut parser, &[
+            "Error:checksum mismatch, Last Line: 5",
+            "Resend: 6",
+            "ok",
+        ]);
+        assert!(matches!(&events[..], [CncEvent::Error(e), CncEvent::Resend(6), CncEvent::Ok] if e == "checksum mismatch, Last Line: 5"));
+    }
+
+    #[test]
+    fn busy_and_start() {
+        let mut parser = MarlinParser::default();
+        let events = decode_all(&mut parser, &["echo:busy: processing", "start", "ok T:21.3 /0.0"]);
+        assert!(matches!(&events[..], [CncEvent::Busy, CncEvent::Start, CncEvent::Ok]));
+    }
+
+    #[test]
+    fn position_report_is_not_an_endstop() {
+        let mut parser = MarlinParser::default();
+        let events = decode_all(&mut parser, &["X:0.00 Y:127.00 Z:145.00 E:0.00 Count X: 0 Y:10160 Z:116000"]);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], CncEvent::PositionReport(_)));
+    }
+}
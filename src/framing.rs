@@ -0,0 +1,147 @@
+use std::fmt::{self, Display};
+
+/// What to do with bytes that are not UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Replace them with U+FFFD and keep the line.
+    Lossy,
+    /// Drop the line and report it.
+    Strict,
+}
+
+/// How a port's byte stream is cut into lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    pub delimiter: u8,
+    /// Longest line in bytes, without its delimiter. Longer lines are dropped.
+    pub max_length: usize,
+    /// Drop a `\r` before the delimiter, for `\r\n` line endings.
+    pub strip_cr: bool,
+    pub utf8: Utf8Policy,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self { delimiter: b'\n', max_length: 1024, strip_cr: true, utf8: Utf8Policy::Lossy }
+    }
+}
+
+/// A line that could not be framed. Reading carries on with the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooLong(usize),
+    NotUtf8,
+}
+
+impl Display for FrameError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::TooLong(max) => write!(fmt, "line longer than {max} bytes dropped"),
+            FrameError::NotUtf8 => fmt.write_str("line that is not UTF-8 dropped"),
+        }
+    }
+}
+
+/// Cuts lines off the front of a read buffer, however the reads split or merged them.
+#[derive(Debug)]
+pub struct LineCodec {
+    framing: Framing,
+    /// Bytes of the buffer already searched for a delimiter.
+    searched: usize,
+    /// Throwing away the rest of a line that was too long.
+    discarding: bool,
+}
+
+impl LineCodec {
+    pub fn new(framing: Framing) -> Self {
+        Self { framing, searched: 0, discarding: false }
+    }
+
+    /// Forgets any partial line, for a port that was just opened.
+    pub fn reset(&mut self, buf: &mut Vec<u8>) {
+        buf.clear();
+        self.searched = 0;
+        self.discarding = false;
+    }
+
+    /// Takes the next line off the front of `buf`. Ok(None) until a whole line has arrived.
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<String>, FrameError> {
+        loop {
+            let found = buf[self.searched..].iter().position(|&b| b == self.framing.delimiter).map(|i| self.searched + i);
+            match found {
+                Some(end) if self.discarding => {
+                    // the end of the long line, start over after it.
+                    buf.drain(..=end);
+                    self.searched = 0;
+                    self.discarding = false;
+                },
+                Some(end) => {
+                    let line: Vec<u8> = buf.drain(..=end).collect();
+                    self.searched = 0;
+                    let mut line = &line[..end];
+                    if self.framing.strip_cr {
+                        line = line.strip_suffix(b"\r").unwrap_or(line);
+                    }
+                    if line.len() > self.framing.max_length {
+                        return Err(FrameError::TooLong(self.framing.max_length));
+                    }
+                    return match self.framing.utf8 {
+                        Utf8Policy::Lossy => Ok(Some(String::from_utf8_lossy(line).into_owned())),
+                        Utf8Policy::Strict => String::from_utf8(line.to_vec()).map(Some).map_err(|_| FrameError::NotUtf8),
+                    };
+                },
+                None if self.discarding => {
+                    buf.clear();
+                    self.searched = 0;
+                    return Ok(None);
+                },
+                // room for a `\r` that is about to be stripped.
+                None if buf.len() > self.framing.max_length + usize::from(self.framing.strip_cr) => {
+                    buf.clear();
+                    self.searched = 0;
+                    self.discarding = true;
+                    return Err(FrameError::TooLong(self.framing.max_length));
+                },
+                None => {
+                    self.searched = buf.len();
+                    return Ok(None);
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(codec: &mut LineCodec, buf: &mut Vec<u8>, chunk: &[u8]) -> Vec<Result<String, FrameError>> {
+        buf.extend_from_slice(chunk);
+        std::iter::from_fn(|| codec.decode(buf).transpose()).collect()
+    }
+
+    #[test]
+    fn split_and_merged_chunks() {
+        let mut codec = LineCodec::new(Framing::default());
+        let mut buf = vec![];
+        assert_eq!(lines(&mut codec, &mut buf, b"o"), []);
+        assert_eq!(lines(&mut codec, &mut buf, b"k\r"), []);
+        assert_eq!(lines(&mut codec, &mut buf, b"\nX:1 Y:2\nok\r\nbusy"), [Ok("ok".to_owned()), Ok("X:1 Y:2".to_owned()), Ok("ok".to_owned())]);
+        assert_eq!(lines(&mut codec, &mut buf, b": processing\n\n"), [Ok("busy: processing".to_owned()), Ok(String::new())]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn long_lines_and_bad_utf8() {
+        let framing = Framing { max_length: 4, utf8: Utf8Policy::Strict, ..Framing::default() };
+        let mut codec = LineCodec::new(framing);
+        let mut buf = vec![];
+        assert_eq!(lines(&mut codec, &mut buf, b"abcd\r\nabcdefg"), [Ok("abcd".to_owned()), Err(FrameError::TooLong(4))]);
+        // the rest of the long line is thrown away, not returned as a line of its own.
+        assert_eq!(lines(&mut codec, &mut buf, b"hij\nok\n"), [Ok("ok".to_owned())]);
+        assert_eq!(lines(&mut codec, &mut buf, b"o\xffk\nok\n"), [Err(FrameError::NotUtf8), Ok("ok".to_owned())]);
+
+        let mut codec = LineCodec::new(Framing { delimiter: b'\r', utf8: Utf8Policy::Lossy, ..framing });
+        assert_eq!(lines(&mut codec, &mut buf, b"o\xffk\r"), [Ok("o\u{fffd}k".to_owned())]);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{collections::VecDeque, fmt::{self, Display}, path::PathBuf};
+use log::{info, warn};
+use tokio::{fs::File, io::{AsyncBufReadExt, BufReader}, sync::{mpsc::{self, error::TryRecvError}, oneshot}};
+
+use crate::{estimate::{MachineLimits, MoveEstimator}, modal::ModalState, preflight::{self, Preflight}, state::TravelLimits};
+
+/// Lines read ahead of the send queue, so memory use does not depend on file size.
+const LOOKAHEAD: usize = 64;
+
+/// Height lifted after an abort to get the tool clear of the work, in mm.
+const ABORT_RETRACT: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting for the pre-flight check and then for the operator to start it.
+    AwaitingConfirmation,
+    /// The pre-flight check found problems, the job never started.
+    Rejected,
+    Running,
+    Paused,
+    Aborted,
+    Finished,
+}
+
+impl Display for JobState {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobState::AwaitingConfirmation => fmt.write_str("confirm"),
+            JobState::Rejected => fmt.write_str("rejected"),
+            JobState::Running => fmt.write_str("running"),
+            JobState::Paused => fmt.write_str("paused"),
+            JobState::Aborted => fmt.write_str("aborted"),
+            JobState::Finished => fmt.write_str("finished"),
+        }
+    }
+}
+
+/// Job progress for the remote, `%:<acknowledged> <total> <percent> <eta seconds>`.
+/// Totals are `?` until the file scan is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub acknowledged: usize,
+    pub total: Option<usize>,
+    pub remaining_seconds: Option<u32>,
+}
+
+impl Display for Progress {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match (self.total, self.remaining_seconds) {
+            (Some(total), Some(eta)) => {
+                let percent = (self.acknowledged * 100).checked_div(total).unwrap_or(100).min(100);
+                write!(fmt, "%:{} {} {} {}", self.acknowledged, total, percent, eta)
+            },
+            _ => write!(fmt, "%:{} ? ? ?", self.acknowledged),
+        }
+    }
+}
+
+enum JobLine {
+    File(String),
+    /// Generated to restore modal state when resuming part way through the file.
+    Preamble(String),
+    /// The file could not be read to the end.
+    Error(std::io::Error),
+}
+
+/// A G-code file being streamed to the controller. The file is read by a
+/// background task a few lines ahead of what has been sent.
+pub struct Job {
+    pub path: PathBuf,
+    pub state: JobState,
+    /// 1 based line the job started from.
+    pub first_line: usize,
+    lines: Option<mpsc::Receiver<JobLine>>,
+    summary: Option<Preflight>,
+    preflight_rx: Option<oneshot::Receiver<std::io::Result<Preflight>>>,
+    estimator: MoveEstimator,
+    handed_out: usize,
+    acknowledged: usize,
+    acknowledged_seconds: f32,
+    // estimated run time of each line handed out but not yet acknowledged.
+    pending_seconds: VecDeque<f32>,
+}
+
+async fn read_lines(path: PathBuf, first_line: usize, controller: ModalState, tx: mpsc::Sender<JobLine>) {
+    let file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("unable to open {}: {}", path.display(), e);
+            let _ = tx.send(JobLine::Error(e)).await;
+            return;
+        },
+    };
+    let mut lines = BufReader::new(file).lines();
+    let mut line_number = 0;
+    let mut modal = ModalState::default();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                line_number += 1;
+                if line_number < first_line {
+                    modal.apply(&line);
+                    continue;
+                }
+                if line_number == first_line && first_line > 1 {
+                    info!("resuming {} at line {} from {:?}", path.display(), first_line, modal);
+                    for preamble in modal.resume_preamble(&controller) {
+                        if tx.send(JobLine::Preamble(preamble)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                // the job was aborted.
+                if tx.send(JobLine::File(line)).await.is_err() {
+                    return;
+                }
+            },
+            Ok(None) => return,
+            Err(e) => {
+                warn!("unable to read {}: {}", path.display(), e);
+                let _ = tx.send(JobLine::Error(e)).await;
+                return;
+            },
+        }
+    }
+}
+
+impl Job {
+    /// Starts at `first_line` (1 based). Lines before it are only read to work
+    /// out the modal state, which a generated preamble restores before going on.
+    ///
+    /// The job waits for confirmation while the whole file is dry run from
+    /// `modal`, the controller's current state, see `poll_preflight`.
+    pub fn start_from(path: PathBuf, first_line: usize, modal: ModalState, limits: Option<TravelLimits>, machine: MachineLimits) -> Self {
+        let first_line = first_line.max(1);
+        let (tx, rx) = mpsc::channel(LOOKAHEAD);
+        tokio::spawn(read_lines(path.clone(), first_line, modal.clone(), tx));
+        let (preflight_tx, preflight_rx) = oneshot::channel();
+        let check_path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = preflight::check(&check_path, first_line, modal, limits, machine);
+            match &result {
+                Ok(summary) => info!("{}: {} lines, about {:.0}s, {} warnings, {} errors",
+                    check_path.display(), summary.lines, summary.seconds, summary.warning_count, summary.error_count),
+                Err(e) => warn!("unable to check {}: {}", check_path.display(), e),
+            }
+            let _ = preflight_tx.send(result);
+        });
+        Self {
+            path,
+            state: JobState::AwaitingConfirmation,
+            first_line,
+            lines: Some(rx),
+            summary: None,
+            preflight_rx: Some(preflight_rx),
+            estimator: MoveEstimator::new(machine),
+            handed_out: first_line - 1,
+            acknowledged: first_line - 1,
+            acknowledged_seconds: 0.0,
+            pending_seconds: VecDeque::new(),
+        }
+    }
+
+    /// Tops `buffer` up to the lookahead with lines read so far. Returns true
+    /// once, on the call that finds the end of the file, or the error that
+    /// stopped it being read to the end.
+    pub fn fill(&mut self, buffer: &mut VecDeque<String>) -> std::io::Result<bool> {
+        let Some(lines) = self.lines.as_mut() else { return Ok(false); };
+        while buffer.len() < LOOKAHEAD {
+            match lines.try_recv() {
+                Ok(JobLine::File(line)) => {
+                    self.pending_seconds.push_back(self.estimator.line_time(&line));
+                    self.handed_out += 1;
+                    buffer.push_back(line);
+                },
+                Ok(JobLine::Preamble(line)) => {
+                    self.estimator.line_time(&line);
+                    buffer.push_back(line);
+                },
+                Ok(JobLine::Error(e)) => {
+                    self.lines = None;
+                    return Err(e);
+                },
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => {
+                    self.lines = None;
+                    return Ok(true);
+                },
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether every line of the file has been handed out by `fill`.
+    pub fn is_read(&self) -> bool {
+        self.lines.is_none()
+    }
+
+    /// The pre-flight result, returned once when the check finishes.
+    pub fn poll_preflight(&mut self) -> Option<std::io::Result<Preflight>> {
+        let result = self.preflight_rx.as_mut()?.try_recv().ok()?;
+        self.preflight_rx = None;
+        if let Ok(summary) = &result {
+            self.summary = Some(summary.clone());
+        }
+        Some(result)
+    }
+
+    /// Whether the pre-flight check finished without errors.
+    pub fn preflight_passed(&self) -> bool {
+        self.summary.as_ref().is_some_and(Preflight::passed)
+    }
+
+    /// Progress, given how many lines handed out by `fill` have not been acknowledged yet.
+    pub fn progress(&mut self, unacknowledged: usize) -> Progress {
+        self.poll_preflight();
+        let acknowledged = self.handed_out.saturating_sub(unacknowledged);
+        while self.acknowledged < acknowledged {
+            self.acknowledged_seconds += self.pending_seconds.pop_front().unwrap_or_default();
+            self.acknowledged += 1;
+        }
+        Progress {
+            acknowledged: self.acknowledged,
+            total: self.summary.as_ref().map(|s| s.lines),
+            remaining_seconds: self.summary.as_ref().map(|s| (s.seconds - self.acknowledged_seconds).max(0.0).round() as u32),
+        }
+    }
+
+    /// Stops the background reader, leaving the rest of the file unread.
+    pub fn stop_reading(&mut self) {
+        self.lines = None;
+    }
+
+    /// Spindle off and lift Z clear of the work, run once an abort has flushed the queue.
+    /// The lift is in mm whatever units the job left active.
+    pub fn abort_commands() -> Vec<String> {
+        vec![
+            "M5".to_owned(),
+            "G21".to_owned(),
+            "G91".to_owned(),
+            format!("G0 Z{ABORT_RETRACT}"),
+            "G90".to_owned(),
+        ]
+    }
+
+    /// State change message for the remote, `J:paused`.
+    pub fn status_message(&self) -> String {
+        format!("J:{}\n", self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fill_reads_lazily_to_the_end() {
+        let path = std::env::temp_dir().join(format!("rpi_cnc_remote_job_{}.nc", std::process::id()));
+        let lines: Vec<_> = (0..LOOKAHEAD * 2).map(|i| format!("G1 X{i}")).collect();
+        std::fs::write(&path, lines.join("\n")).expect("write test file");
+
+        let mut job = Job::start_from(path.clone(), 1, ModalState::default(), None, MachineLimits::default());
+        let mut buffer = VecDeque::new();
+        let mut read = vec![];
+        while !job.fill(&mut buffer).expect("readable") {
+            assert!(buffer.len() <= LOOKAHEAD);
+            read.extend(buffer.drain(..));
+            tokio::task::yield_now().await;
+        }
+        read.extend(buffer.drain(..));
+        assert!(job.is_read());
+        assert_eq!(read, lines);
+
+        let mut progress = job.progress(LOOKAHEAD);
+        while progress.total.is_none() {
+            tokio::task::yield_now().await;
+            progress = job.progress(LOOKAHEAD);
+        }
+        assert_eq!(progress.acknowledged, LOOKAHEAD);
+        assert_eq!(progress.to_string(), format!("%:{} {} 50 {}", LOOKAHEAD, LOOKAHEAD * 2, progress.remaining_seconds.unwrap()));
+        std::fs::remove_file(path).expect("remove test file");
+    }
+
+    #[tokio::test]
+    async fn read_errors_are_not_the_end_of_the_file() {
+        let path = std::env::temp_dir().join(format!("rpi_cnc_remote_unreadable_{}.nc", std::process::id()));
+        std::fs::write(&path, b"G1 X1\nG1 X\xff\nG1 X3\n").expect("write test file");
+
+        let mut job = Job::start_from(path.clone(), 1, ModalState::default(), None, MachineLimits::default());
+        let mut buffer = VecDeque::new();
+        let result = loop {
+            match job.fill(&mut buffer) {
+                Ok(false) => tokio::task::yield_now().await,
+                result => break result,
+            }
+        };
+        assert!(result.is_err());
+        assert!(job.is_read());
+        assert_eq!(buffer, ["G1 X1"]);
+        std::fs::remove_file(path).expect("remove test file");
+    }
+
+    #[test]
+    fn abort_retracts_in_mm_after_an_inch_job() {
+        let mut modal = ModalState::default();
+        modal.apply("G20 G90 G0 Z1");
+        let cut_z = modal.work_position().z;
+        for line in Job::abort_commands() {
+            modal.apply(&line);
+        }
+        assert!((modal.work_position().z - cut_z - ABORT_RETRACT).abs() < 1e-4);
+    }
+
+    #[tokio::test]
+    async fn start_from_line_sends_preamble() {
+        let path = std::env::temp_dir().join(format!("rpi_cnc_remote_resume_{}.nc", std::process::id()));
+        std::fs::write(&path, "G21 G90\nM3 S1000\nG0 Z5\nG0 X1 Y1\nG1 Z-1 F100\nG1 X2\nG1 X3\n").expect("write test file");
+
+        let mut job = Job::start_from(path.clone(), 7, ModalState::default(), None, MachineLimits::default());
+        let mut buffer = VecDeque::new();
+        while !job.fill(&mut buffer).expect("readable") {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(buffer, [
+            "G21", "G54", "G90",
+            "G0 Z5", "G0 X2 Y1",
+            "M3 S1000",
+            "G1 Z-1 F100",
+            "G1 X3",
+        ]);
+        assert_eq!(job.progress(buffer.len()).acknowledged, 6);
+        std::fs::remove_file(path).expect("remove test file");
+    }
+}
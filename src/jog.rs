@@ -0,0 +1,202 @@
+use std::{collections::VecDeque, fmt::{self, Display}, str::FromStr, time::{Duration, Instant}};
+
+use crate::state::Point3;
+
+/// Step sizes the remote may pick, in mm per dial detent.
+const STEPS: [f32; 4] = [0.01, 0.1, 1.0, 10.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JogMode {
+    /// Moves follow the dial, capped per jog and stopped as soon as it stops turning.
+    Continuous,
+    /// Every detent moves exactly one step, however fast the dial turns.
+    Incremental,
+}
+
+impl Display for JogMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JogMode::Continuous => fmt.write_str("continuous"),
+            JogMode::Incremental => fmt.write_str("incremental"),
+        }
+    }
+}
+
+/// Change to the jog settings from the remote, `D:step x 0.1`, `D:lock z`,
+/// `D:incremental` or `D:?` to have the settings sent back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JogCommand {
+    /// Step for one axis (`x`, `y`, `z`) or all of them.
+    Step(Option<char>, f32),
+    Lock(char, bool),
+    Mode(JogMode),
+    Query,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseJogCommandError;
+impl FromStr for JogCommand {
+    type Err = ParseJogCommandError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let axis = |a: &str| match a.to_lowercase().as_str() {
+            a @ ("x" | "y" | "z") => a.chars().next().ok_or(ParseJogCommandError),
+            _ => Err(ParseJogCommandError),
+        };
+        let step = |s: &str| s.parse::<f32>().ok().filter(|s| STEPS.contains(s)).ok_or(ParseJogCommandError);
+        match input.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["step", s] => Ok(JogCommand::Step(None, step(s)?)),
+            ["step", a, s] => Ok(JogCommand::Step(Some(axis(a)?), step(s)?)),
+            ["lock", a] => Ok(JogCommand::Lock(axis(a)?, true)),
+            ["unlock", a] => Ok(JogCommand::Lock(axis(a)?, false)),
+            ["continuous"] => Ok(JogCommand::Mode(JogMode::Continuous)),
+            ["incremental"] => Ok(JogCommand::Mode(JogMode::Incremental)),
+            ["?"] => Ok(JogCommand::Query),
+            _ => Err(ParseJogCommandError),
+        }
+    }
+}
+
+fn set_axis<T>(p: &mut Point3<T>, axis: char, v: T) {
+    match axis {
+        'x' => p.x = v,
+        'y' => p.y = v,
+        _ => p.z = v,
+    }
+}
+
+/// How dial detents turn into jogs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JogSettings {
+    /// mm per detent.
+    pub step: Point3<f32>,
+    pub locked: Point3<bool>,
+    pub mode: JogMode,
+}
+
+impl Default for JogSettings {
+    fn default() -> Self {
+        Self { step: Point3::new_uniform(0.1), locked: Point3::new_uniform(false), mode: JogMode::Continuous }
+    }
+}
+
+impl Display for JogSettings {
+    /// `D:X0.1 Y0.1 Z0.01 locked:Z continuous`, with `locked:-` when none are.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let locked: String = [('X', self.locked.x), ('Y', self.locked.y), ('Z', self.locked.z)].iter()
+            .filter(|(_, locked)| *locked)
+            .map(|(axis, _)| *axis)
+            .collect();
+        let locked = if locked.is_empty() { "-".to_owned() } else { locked };
+        write!(fmt, "D:{} locked:{} {}", self.step, locked, self.mode)
+    }
+}
+
+impl JogSettings {
+    pub fn apply(&mut self, command: JogCommand) {
+        match command {
+            JogCommand::Step(None, step) => self.step = Point3::new_uniform(step),
+            JogCommand::Step(Some(a), step) => set_axis(&mut self.step, a, step),
+            JogCommand::Lock(a, locked) => set_axis(&mut self.locked, a, locked),
+            JogCommand::Mode(mode) => self.mode = mode,
+            JogCommand::Query => {},
+        }
+    }
+
+    /// Relative jog in mm for the dial having turned `detents` on each axis.
+    pub fn jog(&self, detents: Point3<i64>) -> Point3<f32> {
+        let detents = detents.to_f32();
+        let axis = |detents: f32, step: f32, locked: bool| if locked { 0.0 } else { detents * step };
+        Point3::new(
+            axis(detents.x, self.step.x, self.locked.x),
+            axis(detents.y, self.step.y, self.locked.y),
+            axis(detents.z, self.step.z, self.locked.z),
+        )
+    }
+}
+
+/// Dial speed is averaged over this long.
+const VELOCITY_WINDOW: Duration = Duration::from_millis(250);
+
+/// How fast the dial is turning, from the detents seen over the last moment.
+#[derive(Debug, Default)]
+pub struct DialVelocity {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl DialVelocity {
+    /// Notes `detents` turned on any axis at `now`.
+    pub fn record(&mut self, now: Instant, detents: u64) {
+        self.samples.push_back((now, detents));
+        self.forget_before(now);
+    }
+
+    fn forget_before(&mut self, now: Instant) {
+        while self.samples.front().is_some_and(|(at, _)| now.duration_since(*at) > VELOCITY_WINDOW) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Detents per second at `now`.
+    pub fn velocity(&mut self, now: Instant) -> f32 {
+        self.forget_before(now);
+        self.samples.iter().map(|(_, detents)| *detents).sum::<u64>() as f32 / VELOCITY_WINDOW.as_secs_f32()
+    }
+}
+
+/// Makes a fast turning dial move further per detent, like an MPG pendant.
+/// Below `threshold` detents per second a detent is one step. Above it every
+/// extra detent per second adds `gain` steps per detent, up to `max_multiplier`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JogAcceleration {
+    pub threshold: f32,
+    pub gain: f32,
+    pub max_multiplier: f32,
+}
+
+impl Default for JogAcceleration {
+    fn default() -> Self {
+        Self { threshold: 5.0, gain: 0.2, max_multiplier: 10.0 }
+    }
+}
+
+impl JogAcceleration {
+    pub fn multiplier(&self, velocity: f32) -> f32 {
+        (1.0 + self.gain * (velocity - self.threshold).max(0.0)).min(self.max_multiplier.max(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_change_settings() {
+        let mut settings = JogSettings::default();
+        for command in ["step 1", "step z 0.01", "lock Y", "incremental"] {
+            settings.apply(command.parse().expect("parse success"));
+        }
+        assert_eq!(settings.to_string(), "D:X1 Y1 Z0.01 locked:Y incremental");
+        assert_eq!(settings.jog(Point3::new(3, 5, -2)), Point3::new(3.0, 0.0, -0.02));
+
+        settings.apply("unlock y".parse().expect("parse success"));
+        assert_eq!(settings.to_string(), "D:X1 Y1 Z0.01 locked:- incremental");
+        assert!("step 0.5".parse::<JogCommand>().is_err());
+        assert!("lock w".parse::<JogCommand>().is_err());
+    }
+
+    #[test]
+    fn faster_turns_go_further() {
+        let start = Instant::now();
+        let mut velocity = DialVelocity::default();
+        velocity.record(start, 2);
+        velocity.record(start + Duration::from_millis(100), 3);
+        assert_eq!(velocity.velocity(start + Duration::from_millis(200)), 20.0);
+        assert_eq!(velocity.velocity(start + Duration::from_millis(300)), 12.0);
+        assert_eq!(velocity.velocity(start + Duration::from_secs(1)), 0.0);
+
+        let curve = JogAcceleration::default();
+        assert_eq!(curve.multiplier(3.0), 1.0);
+        assert_eq!(curve.multiplier(20.0), 4.0);
+        assert_eq!(curve.multiplier(500.0), 10.0);
+    }
+}
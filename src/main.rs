@@ -1,12 +1,23 @@
 mod state;
 mod state_parser;
+mod firmware;
+mod framing;
 mod port_io;
 mod brain;
+mod discovery;
+mod estimate;
+mod job;
+mod jog;
+mod modal;
+mod preflight;
+mod settings;
+mod streaming;
+mod template;
 
 use std::{env, time::Duration};
 use config::Config;
 use brain::*;
-use log::warn;
+use log::{error, info, warn};
 use port_io::*;
 use state::*;
 use tokio::{io::AsyncBufReadExt, sync::{broadcast, mpsc::{self}}, task::{self}, time::sleep};
@@ -15,60 +26,43 @@ use tokio::{io::AsyncBufReadExt, sync::{broadcast, mpsc::{self}}, task::{self},
 async fn main() {
     env::set_var("RUST_LOG", "info");
     colog::init();
-    let config: Config = Config::builder()
-        .set_default("XBEE_PORT", "/dev/ttyAMA0").unwrap()
-        .set_default("XBEE_BAUD", "9600").unwrap()
-        .set_default("CNC_PORT", "/dev/ttyUSB0").unwrap()
-        .set_default("CNC_BAUD", "115200").unwrap()
-        .add_source(
-            config::Environment::with_prefix("CNC").try_parsing(true),
-        )
-        .build()
-        .unwrap();
+    let (config, profile): (Config, Option<String>) = match settings::load(None) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("unable to load configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    let brain_config = match settings::brain_config(&config) {
+        Ok(brain_config) => BrainConfig { profile, ..brain_config },
+        Err(e) => {
+            error!("bad configuration: {}", e);
+            std::process::exit(1);
+        },
+    };
+    info!("machine profile: {}", brain_config.profile.as_deref().unwrap_or("none"));
+    let firmware = brain_config.firmware;
+    info!("cnc firmware: {:?}", firmware);
     //let port_baud: u32 = config.get_int("XBEE_BAUD").expect("Unable to find baud rate for xbee") as u32;
     let (xbee_config_tx, xbee_config_rx) = mpsc::channel::<SerialPortInfo>(2);
     let (cnc_config_tx, cnc_config_rx) = mpsc::channel::<SerialPortInfo>(2);
-    let (xbee_data_tx, xbee_data_rx) = mpsc::channel::<String>(32);
+    let (xbee_data_tx, xbee_data_rx) = mpsc::channel::<PortMessage>(32);
     let (xbee_events_tx, xbee_events_rx) = broadcast::channel::<RemoteEvent>(32);
-    let (cnc_data_tx, cnc_data_rx) = mpsc::channel::<String>(32);
+    let (cnc_data_tx, cnc_data_rx) = mpsc::channel::<PortMessage>(32);
     let (cnc_events_tx, cnc_events_rx) = broadcast::channel::<CncEvent>(32);
 
-    port_info_from_config("XBEE", &config, &xbee_config_tx).await;
-    port_info_from_config("CNC", &config, &cnc_config_tx).await;
-
-    for dev in nusb::list_devices().unwrap() {
-        if let Some(product) = dev.product_string() {
-            let lower = product.to_lowercase();
-            let mut cont = false;
-            for black_list_item in ["keyboard", "mouse", "camera", "webcam", "gaming", "host controller", "mystic light", "usb2.0 hub", "otg controller"] {
-                if lower.contains(black_list_item) {
-                    cont = true;
-                    break;
-                }
-            }
-            if cont {
-                continue;
-            }
-        }
-        //println!("{:#?}", dev);
-        //if let Some(man) = dev.manufacturer_string() {
-            //let lower = man.to_lowercase();
-            //for white_list_item in ["arduino"] {
-                //if lower.contains(white_list_item) {
-                    //println!("{:#?}", dev);
-                //}
-            //}
-        //}
-    }
+    // the port tasks have not started yet, the channels have room for these.
+    let _ = xbee_config_tx.try_send(brain_config.xbee_port.clone());
+    let _ = cnc_config_tx.try_send(brain_config.cnc_port.clone());
 
     let local = task::LocalSet::new();
     local.run_until(async move {
-        let xbee_io = task::spawn_local(uart_read_write(xbee_config_rx, xbee_data_rx, xbee_events_tx));
-        let cnc_io = task::spawn_local(uart_read_write(cnc_config_rx, cnc_data_rx, cnc_events_tx));
+        let xbee_io = task::spawn_local(uart_read_write(xbee_config_rx, xbee_data_rx, xbee_events_tx, FromStrDecoder::default()));
+        let cnc_io = task::spawn_local(uart_read_write(cnc_config_rx, cnc_data_rx, cnc_events_tx, firmware.decoder()));
         //let cnc_io = task::spawn_local(fake_cnc_port(cnc_config_rx, cnc_data_rx, cnc_events_tx));
         //let cnc_data_tx_startup = cnc_data_tx.clone();
         let readline_input = cnc_data_tx.clone();
-        let brain_loop = task::spawn_local(event_brain_loop(xbee_events_rx, xbee_data_tx, cnc_events_rx, cnc_data_tx));
+        let brain_loop = task::spawn_local(event_brain_loop(xbee_events_rx, xbee_data_tx, cnc_events_rx, cnc_data_tx, xbee_config_tx, cnc_config_tx, brain_config));
 
         //task::spawn_local(async move {
             //sleep(Duration::from_secs_f32(0.5)).await;
@@ -80,13 +74,19 @@ async fn main() {
             let reader = tokio::io::BufReader::new(stdin);
             let mut lines = reader.lines();
             while let Ok(Some(line)) = lines.next_line().await {
-                readline_input.send(line).await.unwrap();
+                if readline_input.send(PortMessage::Line(line)).await.is_err() {
+                    break;
+                }
             }
             warn!("fin console input");
         });
 
-        brain_loop.await.unwrap();
-        xbee_io.await.unwrap();
-        cnc_io.await.unwrap();
+        match brain_loop.await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => error!("brain stopped: {}", e),
+            Err(e) => error!("brain task failed: {}", e),
+        }
+        xbee_io.abort();
+        cnc_io.abort();
     }).await;
 }
@@ -0,0 +1,634 @@
+use std::f32::consts::TAU;
+
+use gcode::{GCode, Mnemonic, Word};
+
+use crate::{state::Point3, streaming::strip_comments};
+
+const MM_PER_INCH: f32 = 25.4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceMode {
+    Absolute,
+    Relative,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Millimeters,
+    Inches,
+}
+
+impl Units {
+    fn to_mm(self, v: f32) -> f32 {
+        match self {
+            Units::Millimeters => v,
+            Units::Inches => v * MM_PER_INCH,
+        }
+    }
+
+    fn in_units(self, v: f32) -> f32 {
+        match self {
+            Units::Millimeters => v,
+            Units::Inches => v / MM_PER_INCH,
+        }
+    }
+}
+
+/// Arc plane, G17 to G19.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plane {
+    XY,
+    ZX,
+    YZ,
+}
+
+impl Plane {
+    /// `p` as the plane's first and second axes, plus the axis normal to it.
+    /// G18 runs Z then X so that G2 stays clockwise looking down the normal.
+    fn split(self, p: Point3<f32>) -> (f32, f32, f32) {
+        match self {
+            Plane::XY => (p.x, p.y, p.z),
+            Plane::ZX => (p.z, p.x, p.y),
+            Plane::YZ => (p.y, p.z, p.x),
+        }
+    }
+
+    /// Inverse of `split`.
+    fn join(self, a: f32, b: f32, normal: f32) -> Point3<f32> {
+        match self {
+            Plane::XY => Point3::new(a, b, normal),
+            Plane::ZX => Point3::new(b, normal, a),
+            Plane::YZ => Point3::new(normal, a, b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spindle {
+    Off,
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveKind {
+    Rapid,
+    Linear,
+    /// Helical when the end differs from the start along the plane's normal.
+    Arc { clockwise: bool, plane: Plane, center: Point3<f32> },
+}
+
+/// A move made by one motion command, in mm and machine coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Move {
+    pub kind: MoveKind,
+    pub from: Point3<f32>,
+    pub to: Point3<f32>,
+    /// mm/min, None for rapids or before any F word.
+    pub feed_rate: Option<f32>,
+}
+
+impl Move {
+    /// Path length in mm.
+    pub fn length(&self) -> f32 {
+        match self.kind {
+            MoveKind::Arc { plane, center, .. } => {
+                let (a, b, _) = plane.split(self.from.sub(center));
+                let (_, _, from_normal) = plane.split(self.from);
+                let (_, _, to_normal) = plane.split(self.to);
+                let radius = (a * a + b * b).sqrt();
+                (radius * self.sweep()).hypot(to_normal - from_normal)
+            },
+            _ => {
+                let d = self.to.sub(self.from);
+                d.mul(d).sum().sqrt()
+            },
+        }
+    }
+
+    /// Points along the move after its start, close enough together to find
+    /// how far an arc bulges out past its endpoints.
+    pub fn points(&self) -> Vec<Point3<f32>> {
+        let MoveKind::Arc { plane, center, .. } = self.kind else { return vec![self.to]; };
+        let sweep = self.sweep();
+        // a point every 5 degrees.
+        let steps = (sweep.abs() / TAU * 72.0).ceil().max(1.0) as usize;
+        let (start_a, start_b, from_normal) = plane.split(self.from.sub(center));
+        let (_, _, to_normal) = plane.split(self.to.sub(center));
+        let (center_a, center_b, center_normal) = plane.split(center);
+        let mut points: Vec<_> = (1..steps).map(|i| {
+            let t = i as f32 / steps as f32;
+            let (sin, cos) = (sweep * t).sin_cos();
+            plane.join(
+                center_a + start_a * cos - start_b * sin,
+                center_b + start_a * sin + start_b * cos,
+                center_normal + from_normal + (to_normal - from_normal) * t,
+            )
+        }).collect();
+        points.push(self.to);
+        points
+    }
+
+    /// Angle an arc turns through in radians, negative going clockwise. A full
+    /// circle when it ends where it started. 0 for straight moves.
+    pub fn sweep(&self) -> f32 {
+        let MoveKind::Arc { clockwise, plane, center } = self.kind else { return 0.0; };
+        let (start_a, start_b, _) = plane.split(self.from.sub(center));
+        let (end_a, end_b, _) = plane.split(self.to.sub(center));
+        let sweep = (start_a * end_b - start_b * end_a).atan2(start_a * end_a + start_b * end_b);
+        // same tolerance GRBL uses to tell a full circle from no turn at all.
+        const EPSILON: f32 = 5e-7;
+        match clockwise {
+            true if sweep >= -EPSILON => sweep - TAU,
+            false if sweep <= EPSILON => sweep + TAU,
+            _ => sweep,
+        }
+    }
+}
+
+/// Whether `command` is understood, either tracked here or known to have no
+/// effect on the modal state (M110 line numbers, M114 and M119 reports, ...).
+pub fn supports(command: &GCode) -> bool {
+    matches!(
+        (command.mnemonic(), command.major_number(), command.minor_number()),
+        (Mnemonic::General, 0..=4 | 10 | 17..=21 | 28 | 53..=59 | 90 | 91, _)
+        | (Mnemonic::General, 92, 0 | 1)
+        | (Mnemonic::Miscellaneous, 0..=9 | 30 | 110 | 114 | 119 | 400 | 410, _)
+        | (Mnemonic::ToolChange, _, _)
+    )
+}
+
+/// Modal G-code state, as the controller would have it after running the lines
+/// given to `apply`. Positions are tracked in mm in machine coordinates, with
+/// work offsets only known once the G-code sets them (G10, G92).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModalState {
+    pub distance: DistanceMode,
+    pub units: Units,
+    pub plane: Plane,
+    /// 54 to 59 for G54 to G59.
+    pub wcs: u32,
+    /// Active motion command, 0 to 3 for G0 to G3.
+    pub motion: u32,
+    /// In program units per minute.
+    pub feed_rate: Option<f32>,
+    pub spindle: Spindle,
+    pub spindle_speed: Option<f32>,
+    pub tool: Option<u32>,
+    pub machine_position: Point3<f32>,
+    /// G54 to G59 offsets in mm.
+    pub wcs_offsets: [Point3<f32>; 6],
+    /// G92 offset in mm, on top of the work offset.
+    pub g92_offset: Point3<f32>,
+    /// Highest work Z reached in mm, taken as the clearance height.
+    pub max_z: Option<f32>,
+}
+
+impl Default for ModalState {
+    fn default() -> Self {
+        Self {
+            distance: DistanceMode::Absolute,
+            units: Units::Millimeters,
+            plane: Plane::XY,
+            wcs: 54,
+            motion: 0,
+            feed_rate: None,
+            spindle: Spindle::Off,
+            spindle_speed: None,
+            tool: None,
+            machine_position: Point3::default(),
+            wcs_offsets: [Point3::default(); 6],
+            g92_offset: Point3::default(),
+            max_z: None,
+        }
+    }
+}
+
+fn axis_words(command: &GCode) -> Point3<Option<f32>> {
+    Point3::new(command.value_for('X'), command.value_for('Y'), command.value_for('Z'))
+}
+
+impl ModalState {
+    /// Total offset from machine to work coordinates, in mm.
+    fn work_offset(&self) -> Point3<f32> {
+        self.wcs_offsets[(self.wcs - 54) as usize].add(self.g92_offset)
+    }
+
+    /// Position in the active work coordinates, in mm.
+    pub fn work_position(&self) -> Point3<f32> {
+        self.machine_position.sub(self.work_offset())
+    }
+
+    /// Moves the tracked position to where the controller says it is, in work coordinates and mm.
+    pub fn set_work_position(&mut self, position: Point3<f32>) {
+        self.machine_position = position.add(self.work_offset());
+    }
+
+    /// Takes both positions from a controller that reports them, such as GRBL
+    /// whose work offsets live in its EEPROM. The difference becomes the active
+    /// work offset, on top of any G92 offset already known.
+    pub fn set_positions(&mut self, machine: Point3<f32>, work: Point3<f32>) {
+        self.machine_position = machine;
+        self.wcs_offsets[(self.wcs - 54) as usize] = machine.sub(work).sub(self.g92_offset);
+    }
+
+    /// Position in the active work coordinates and program units.
+    pub fn program_position(&self) -> Point3<f32> {
+        self.work_position().apply(|v| self.units.in_units(v))
+    }
+
+    /// Parses a line. Words without a command (`X10 Y5`, `F300`) continue the
+    /// active motion command, which the parser would otherwise drop. So do axis
+    /// words the parser hands to a non-motion command, as in `G91 X1`.
+    pub fn parse_line(&self, line: &str) -> Vec<GCode> {
+        let mut commands: Vec<_> = gcode::parse(line).collect();
+        if commands.is_empty() && !strip_comments(line).is_empty() {
+            return gcode::parse(&format!("G{} {}", self.motion, line)).collect();
+        }
+        let takes_axes = |c: &GCode| c.mnemonic() == Mnemonic::General && matches!(c.major_number(), 0..=3 | 10 | 28 | 38 | 92);
+        if commands.iter().any(takes_axes) {
+            return commands;
+        }
+        let is_axis = |w: &Word| matches!(w.letter.to_ascii_uppercase(), 'X' | 'Y' | 'Z' | 'I' | 'J' | 'K' | 'R');
+        let mut motion: Option<GCode> = None;
+        for command in commands.iter_mut().filter(|c| c.arguments().iter().any(is_axis)) {
+            let number = command.major_number() as f32 + command.minor_number() as f32 / 10.0;
+            let mut rest = GCode::new(command.mnemonic(), number, command.span());
+            for word in command.arguments() {
+                if is_axis(word) {
+                    motion = Some(motion.unwrap_or_else(|| GCode::new(Mnemonic::General, self.motion as f32, word.span)).with_argument(*word));
+                } else {
+                    rest = rest.with_argument(*word);
+                }
+            }
+            *command = rest;
+        }
+        commands.extend(motion);
+        commands
+    }
+
+    /// Runs a line, returning the moves it makes.
+    pub fn apply(&mut self, line: &str) -> Vec<Move> {
+        let commands = self.parse_line(line);
+        // G53 is not modal, it makes this line's moves use machine coordinates.
+        let machine_coordinates = commands.iter().any(|c| c.mnemonic() == Mnemonic::General && c.major_number() == 53);
+        commands.iter().filter_map(|command| self.apply_command(command, machine_coordinates)).collect()
+    }
+
+    /// Runs the G-code of a GRBL `$J=` jog. Its modal words only apply to the
+    /// jog itself, so only the position carries over.
+    pub fn apply_jog(&mut self, jog: &str) {
+        let mut jogging = self.clone();
+        jogging.apply(jog);
+        self.machine_position = jogging.machine_position;
+    }
+
+    /// Machine position `words` would move to, in mm.
+    fn target(&self, words: Point3<Option<f32>>, machine_coordinates: bool) -> Point3<f32> {
+        let words = Point3::new(
+            words.x.map(|v| self.units.to_mm(v)),
+            words.y.map(|v| self.units.to_mm(v)),
+            words.z.map(|v| self.units.to_mm(v)),
+        );
+        let offset = if machine_coordinates { Point3::default() } else { self.work_offset() };
+        let axis = |word: Option<f32>, current: f32, offset: f32| match (word, self.distance) {
+            (None, _) => current,
+            (Some(v), DistanceMode::Relative) if !machine_coordinates => current + v,
+            (Some(v), _) => v + offset,
+        };
+        let p = self.machine_position;
+        Point3::new(axis(words.x, p.x, offset.x), axis(words.y, p.y, offset.y), axis(words.z, p.z, offset.z))
+    }
+
+    /// Machine position of the centre of an arc from the current position to
+    /// `to`, given either as I/J/K offsets from the start or as an R radius.
+    fn arc_center(&self, command: &GCode, to: Point3<f32>, clockwise: bool) -> Point3<f32> {
+        let from = self.machine_position;
+        let (_, _, normal) = self.plane.split(from);
+        if let Some(radius) = command.value_for('R').map(|r| self.units.to_mm(r)) {
+            // GRBL's construction: the centre sits on the perpendicular bisector of
+            // the chord, on the side that makes the turn the short way round for a
+            // positive R and the long way round for a negative one.
+            let (x, y, _) = self.plane.split(to.sub(from));
+            let chord = x.hypot(y);
+            if chord == 0.0 {
+                return from;
+            }
+            let mut h = -(4.0 * radius * radius - chord * chord).max(0.0).sqrt() / chord;
+            if !clockwise {
+                h = -h;
+            }
+            if radius < 0.0 {
+                h = -h;
+            }
+            let (a, b, _) = self.plane.split(from);
+            return self.plane.join(a + (x - y * h) / 2.0, b + (y + x * h) / 2.0, normal);
+        }
+        let offset = |word: char| command.value_for(word).map_or(0.0, |v| self.units.to_mm(v));
+        let (a, b, _) = self.plane.split(from.add(Point3::new(offset('I'), offset('J'), offset('K'))));
+        self.plane.join(a, b, normal)
+    }
+
+    fn apply_command(&mut self, command: &GCode, machine_coordinates: bool) -> Option<Move> {
+        if let Some(f) = command.value_for('F') {
+            self.feed_rate = Some(f);
+        }
+        if let Some(s) = command.value_for('S') {
+            self.spindle_speed = Some(s);
+        }
+        match (command.mnemonic(), command.major_number(), command.minor_number()) {
+            (Mnemonic::General, n @ 0..=3, _) => {
+                self.motion = n;
+                let to = self.target(axis_words(command), machine_coordinates);
+                let kind = match n {
+                    0 => MoveKind::Rapid,
+                    1 => MoveKind::Linear,
+                    n => MoveKind::Arc { clockwise: n == 2, plane: self.plane, center: self.arc_center(command, to, n == 2) },
+                };
+                let feed_rate = match kind {
+                    MoveKind::Rapid => None,
+                    _ => self.feed_rate.map(|f| self.units.to_mm(f)),
+                };
+                let m = Move { kind, from: self.machine_position, to, feed_rate };
+                self.machine_position = to;
+                let z = self.work_position().z;
+                self.max_z = Some(self.max_z.map_or(z, |max| max.max(z)));
+                return Some(m);
+            },
+            (Mnemonic::General, 10, _) => {
+                let p = command.value_for('P').map_or(self.wcs - 53, |p| p as u32);
+                let index = if p == 0 { self.wcs - 54 } else { p - 1 } as usize;
+                let words = axis_words(command);
+                let offset = self.wcs_offsets.get(index).copied()?;
+                let mut next = offset;
+                for (word, offset, machine, g92) in [
+                    (words.x, &mut next.x, self.machine_position.x, self.g92_offset.x),
+                    (words.y, &mut next.y, self.machine_position.y, self.g92_offset.y),
+                    (words.z, &mut next.z, self.machine_position.z, self.g92_offset.z),
+                ] {
+                    if let Some(v) = word.map(|v| self.units.to_mm(v)) {
+                        match command.value_for('L').map(|l| l as u32) {
+                            // L2 sets the offset itself.
+                            Some(2) => *offset = v,
+                            // L20 sets it so the current position reads as the given value.
+                            Some(20) => *offset = machine - g92 - v,
+                            _ => {},
+                        }
+                    }
+                }
+                self.wcs_offsets[index] = next;
+            },
+            (Mnemonic::General, 17, _) => { self.plane = Plane::XY; },
+            (Mnemonic::General, 18, _) => { self.plane = Plane::ZX; },
+            (Mnemonic::General, 19, _) => { self.plane = Plane::YZ; },
+            (Mnemonic::General, 20, _) => { self.units = Units::Inches; },
+            (Mnemonic::General, 21, _) => { self.units = Units::Millimeters; },
+            (Mnemonic::General, 28, _) => {
+                // home the given axes, or all of them.
+                let words = axis_words(command);
+                let all = words.x.is_none() && words.y.is_none() && words.z.is_none();
+                let from = self.machine_position;
+                let home = |word: Option<f32>, v: f32| if all || word.is_some() { 0.0 } else { v };
+                let to = Point3::new(home(words.x, from.x), home(words.y, from.y), home(words.z, from.z));
+                self.machine_position = to;
+                return Some(Move { kind: MoveKind::Rapid, from, to, feed_rate: None });
+            },
+            (Mnemonic::General, n @ 54..=59, _) => { self.wcs = n; },
+            (Mnemonic::General, 90, _) => { self.distance = DistanceMode::Absolute; },
+            (Mnemonic::General, 91, _) => { self.distance = DistanceMode::Relative; },
+            (Mnemonic::General, 92, 0) => {
+                let words = axis_words(command);
+                let base = self.machine_position.sub(self.wcs_offsets[(self.wcs - 54) as usize]);
+                let set = |word: Option<f32>, base: f32, current: f32| word.map_or(current, |v| base - self.units.to_mm(v));
+                self.g92_offset = Point3::new(
+                    set(words.x, base.x, self.g92_offset.x),
+                    set(words.y, base.y, self.g92_offset.y),
+                    set(words.z, base.z, self.g92_offset.z),
+                );
+            },
+            (Mnemonic::General, 92, 1) => { self.g92_offset = Point3::default(); },
+            (Mnemonic::Miscellaneous, 3, _) => { self.spindle = Spindle::Clockwise; },
+            (Mnemonic::Miscellaneous, 4, _) => { self.spindle = Spindle::CounterClockwise; },
+            (Mnemonic::Miscellaneous, 5, _) => { self.spindle = Spindle::Off; },
+            (Mnemonic::ToolChange, n, _) => { self.tool = Some(n); },
+            _ => {},
+        }
+        None
+    }
+
+    /// Lines that put a controller into this state from wherever it is: restore
+    /// the modes, lift to the clearance height, move over the position, start
+    /// the spindle and plunge at the feed rate. `controller` is where the
+    /// controller is now, used to set any G92 offset it lost in a restart.
+    pub fn resume_preamble(&self, controller: &ModalState) -> Vec<String> {
+        let p = self.program_position();
+        let safe_z = self.max_z.map_or(p.z, |z| self.units.in_units(z)).max(p.z);
+        let mut lines = vec![
+            match self.units {
+                Units::Millimeters => "G21".to_owned(),
+                Units::Inches => "G20".to_owned(),
+            },
+            format!("G{}", self.wcs),
+            "G90".to_owned(),
+        ];
+        if self.g92_offset != Point3::default() {
+            // G92 says where the controller is now, in the coordinates the offset makes.
+            let at = controller.machine_position
+                .sub(controller.wcs_offsets[(self.wcs - 54) as usize])
+                .sub(self.g92_offset)
+                .apply(|v| self.units.in_units(v));
+            lines.push(format!("G92 {at}"));
+        }
+        match self.plane {
+            Plane::XY => {},
+            Plane::ZX => lines.push("G18".to_owned()),
+            Plane::YZ => lines.push("G19".to_owned()),
+        }
+        if let Some(tool) = self.tool {
+            lines.push(format!("T{tool}"));
+        }
+        lines.push(format!("G0 Z{safe_z}"));
+        lines.push(format!("G0 X{} Y{}", p.x, p.y));
+        let speed = self.spindle_speed.map(|s| format!(" S{s}")).unwrap_or_default();
+        match self.spindle {
+            Spindle::Off => lines.push("M5".to_owned()),
+            Spindle::Clockwise => lines.push(format!("M3{speed}")),
+            Spindle::CounterClockwise => lines.push(format!("M4{speed}")),
+        }
+        let feed = self.feed_rate.map(|f| format!(" F{f}")).unwrap_or_default();
+        lines.push(format!("G1 Z{}{feed}", p.z));
+        if self.motion != 1 {
+            lines.push(format!("G{}", self.motion));
+        }
+        if self.distance == DistanceMode::Relative {
+            lines.push("G91".to_owned());
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(lines: &[&str]) -> ModalState {
+        let mut state = ModalState::default();
+        for line in lines {
+            state.apply(line);
+        }
+        state
+    }
+
+    #[test]
+    fn tracks_modal_words() {
+        let state = run(&["G21 G55 G18", "T2 M6", "M3 S12000", "G0 Z0.5", "G1 Z-0.1 F20", "X1 Y2", "G91", "X1"]);
+        assert_eq!(state.units, Units::Millimeters);
+        assert_eq!(state.plane, Plane::ZX);
+        assert_eq!(state.wcs, 55);
+        assert_eq!(state.tool, Some(2));
+        assert_eq!(state.spindle, Spindle::Clockwise);
+        assert_eq!(state.spindle_speed, Some(12000.0));
+        assert_eq!(state.feed_rate, Some(20.0));
+        assert_eq!(state.distance, DistanceMode::Relative);
+        assert_eq!(state.work_position(), Point3::new(2.0, 2.0, -0.1));
+        assert_eq!(state.max_z, Some(0.5));
+    }
+
+    #[test]
+    fn absolute_and_relative() {
+        let state = run(&["G90", "G0 X10 Y10", "G0 X5"]);
+        assert_eq!(state.work_position(), Point3::new(5.0, 10.0, 0.0));
+        let state = run(&["G91", "G0 X10 Y10", "G0 X5"]);
+        assert_eq!(state.work_position(), Point3::new(15.0, 10.0, 0.0));
+        let state = run(&["G1 F100", "G91 X10", "G90 Y10"]);
+        assert_eq!(state.work_position(), Point3::new(10.0, 10.0, 0.0));
+        assert_eq!(state.distance, DistanceMode::Absolute);
+    }
+
+    #[test]
+    fn jogs_only_move() {
+        let mut state = run(&["G90 G0 X1"]);
+        state.apply_jog("G91 G20 X1 F100");
+        assert_eq!(state.motion, 0);
+        assert_eq!(state.distance, DistanceMode::Absolute);
+        assert_eq!(state.units, Units::Millimeters);
+        assert_eq!(state.feed_rate, None);
+        assert_eq!(state.work_position(), Point3::new(26.4, 0.0, 0.0));
+    }
+
+    #[test]
+    fn inches_are_tracked_in_mm() {
+        let mut state = run(&["G20 G90"]);
+        let moves = state.apply("G1 X1 F10");
+        assert_eq!(state.work_position(), Point3::new(25.4, 0.0, 0.0));
+        assert_eq!(state.program_position(), Point3::new(1.0, 0.0, 0.0));
+        assert_eq!(moves[0].feed_rate, Some(254.0));
+        assert_eq!(moves[0].length(), 25.4);
+    }
+
+    #[test]
+    fn work_offsets() {
+        let mut state = run(&["G10 L2 P2 X100 Y50", "G55", "G0 X1 Y1"]);
+        assert_eq!(state.machine_position, Point3::new(101.0, 51.0, 0.0));
+        assert_eq!(state.work_position(), Point3::new(1.0, 1.0, 0.0));
+
+        state.apply("G92 X0 Y0");
+        assert_eq!(state.work_position(), Point3::new(0.0, 0.0, 0.0));
+        state.apply("G0 X2");
+        assert_eq!(state.machine_position, Point3::new(103.0, 51.0, 0.0));
+        state.apply("G92.1");
+        assert_eq!(state.work_position(), Point3::new(3.0, 1.0, 0.0));
+
+        state.apply("G53 G0 X0 Y0");
+        assert_eq!(state.machine_position, Point3::new(0.0, 0.0, 0.0));
+        state.apply("G10 L20 P0 X0");
+        assert_eq!(state.work_position(), Point3::new(0.0, -50.0, 0.0));
+
+        // offsets the controller knows but were never sent through here.
+        let mut state = ModalState::default();
+        state.set_positions(Point3::new(150.0, 80.0, -5.0), Point3::new(50.0, 30.0, 10.0));
+        assert_eq!(state.machine_position, Point3::new(150.0, 80.0, -5.0));
+        assert_eq!(state.work_position(), Point3::new(50.0, 30.0, 10.0));
+    }
+
+    #[test]
+    fn arcs_end_at_their_endpoint() {
+        let mut state = run(&["G90 G17", "G0 X10 Y0"]);
+        let moves = state.apply("G2 X0 Y-10 I-10 J0 F100");
+        assert!(matches!(moves[0].kind, MoveKind::Arc { clockwise: true, .. }));
+        assert_eq!(state.work_position(), Point3::new(0.0, -10.0, 0.0));
+        assert_eq!(state.motion, 2);
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    #[test]
+    fn arc_centers_and_lengths() {
+        let quarter = TAU / 4.0 * 10.0;
+        // clockwise quarter turn in XY, centre given by offset and by radius.
+        let mut state = run(&["G17", "G0 X10 Y0"]);
+        let m = state.apply("G2 X0 Y-10 I-10 J0 F100")[0];
+        assert_eq!(m.kind, MoveKind::Arc { clockwise: true, plane: Plane::XY, center: Point3::default() });
+        assert_near(m.length(), quarter);
+        let mut state = run(&["G17", "G0 X10 Y0"]);
+        let m = state.apply("G2 X0 Y-10 R10")[0];
+        let MoveKind::Arc { center, .. } = m.kind else { panic!("expected an arc") };
+        assert_near(center.x, 0.0);
+        assert_near(center.y, 0.0);
+        assert_near(m.sweep(), -TAU / 4.0);
+
+        // a negative radius takes the long way round.
+        let mut state = run(&["G0 X10 Y0"]);
+        let m = state.apply("G3 X0 Y-10 R-10")[0];
+        assert_near(m.sweep(), TAU * 3.0 / 4.0);
+
+        // full circle in ZX with a helical climb along Y.
+        let mut state = run(&["G18", "G0 X10 Z0"]);
+        let m = state.apply("G3 X10 Z0 Y5 I-10 K0")[0];
+        assert_near(m.sweep(), TAU);
+        assert_near(m.length(), (TAU * 10.0).hypot(5.0));
+
+        // quarter turn in YZ.
+        let mut state = run(&["G19", "G0 Y10 Z0"]);
+        let m = state.apply("G2 Y0 Z-10 J-10")[0];
+        assert_near(m.length(), quarter);
+        assert_eq!(state.work_position(), Point3::new(0.0, 0.0, -10.0));
+    }
+
+    #[test]
+    fn arc_points_follow_the_arc() {
+        let mut state = run(&["G0 X10 Y0"]);
+        let m = state.apply("G3 X-10 Y0 I-10")[0];
+        let points = m.points();
+        assert_eq!(points.len(), 36);
+        assert_eq!(*points.last().unwrap(), Point3::new(-10.0, 0.0, 0.0));
+        let top = points.iter().map(|p| p.y).fold(f32::MIN, f32::max);
+        assert_near(top, 10.0);
+        assert!(points.iter().all(|p| (p.x.hypot(p.y) - 10.0).abs() < 1e-3));
+    }
+
+    #[test]
+    fn resume_preamble_lifts_moves_and_plunges() {
+        let state = run(&["G21 G90", "M3 S10000", "G0 Z5", "G0 X10 Y10", "G1 Z-1 F300", "G1 X20"]);
+        assert_eq!(state.resume_preamble(&ModalState::default()), [
+            "G21", "G54", "G90",
+            "G0 Z5", "G0 X20 Y10",
+            "M3 S10000",
+            "G1 Z-1 F300",
+        ]);
+
+        // the file zeroed X with G92 at X20, the restarted controller is at X50 Y0 Z0.
+        let state = run(&["G21 G90", "G0 Z5", "G0 X20 Y10", "G92 X0", "G0 X5"]);
+        let controller = ModalState { machine_position: Point3::new(50.0, 0.0, 0.0), ..ModalState::default() };
+        assert_eq!(state.resume_preamble(&controller), [
+            "G21", "G54", "G90",
+            "G92 X30 Y0 Z0",
+            "G0 Z5", "G0 X5 Y10",
+            "M5",
+            "G1 Z5",
+            "G0",
+        ]);
+    }
+}
@@ -1,118 +1,318 @@
-use std::{collections::VecDeque, path::Path, str::{from_utf8, FromStr}, time::{Duration, Instant}};
-use config::Config;
-use gcode::Mnemonic;
+use std::{collections::VecDeque, io, sync::Arc, fmt::{self, Display}, marker::PhantomData, path::Path, str::FromStr, time::{Duration, Instant}};
 use log::{info, warn};
-use tokio::{io::AsyncWriteExt, sync::{broadcast, mpsc::{Receiver, Sender}}, task::yield_now, time::sleep};
-use tokio_serial::SerialPortBuilderExt;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::{broadcast, mpsc::{error::SendError, Receiver}}, task::yield_now, time::sleep};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
-use crate::state::{CncEvent, Point3};
+use crate::discovery::{self, UsbId};
+use crate::estimate::MachineLimits;
+use crate::framing::{FrameError, Framing, LineCodec};
+use crate::firmware::Firmware;
+use crate::modal::{self, ModalState};
+use crate::state::CncEvent;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SerialPortInfo {
     pub path: String,
     pub baud: u32,
+    /// Dialect of the controller on the port, so its decoder can follow a change of machine.
+    pub firmware: Option<Firmware>,
+    /// USB devices besides the usual serial chips that may be the controller when `path` is `auto`.
+    pub usb_ids: Vec<UsbId>,
+    pub framing: Framing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMessage {
+    /// Text terminated with a newline on write.
+    Line(String),
+    /// A single byte written as-is, such as GRBL's real-time commands.
+    Realtime(u8),
+}
+
+impl PortMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PortMessage::Line(line) => {
+                let mut bytes = line.as_bytes().to_vec();
+                if bytes.last() != Some(&b'\n') {
+                    bytes.push(b'\n');
+                }
+                bytes
+            },
+            PortMessage::Realtime(byte) => vec![*byte],
+        }
+    }
+}
+
+impl From<String> for PortMessage {
+    fn from(line: String) -> Self { PortMessage::Line(line) }
+}
+
+impl Display for PortMessage {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortMessage::Line(line) => fmt.write_str(line.trim_end()),
+            PortMessage::Realtime(byte) => write!(fmt, "0x{byte:02x}"),
+        }
+    }
+}
+
+/// Something that went wrong with a port or the channels to its task.
+#[derive(Debug, Clone)]
+pub enum PortError {
+    /// Nothing at the port's path, or no controller found when it is `auto`.
+    Missing(String),
+    Open(String, Arc<io::Error>),
+    Read(String, Arc<io::Error>),
+    Write(String, Arc<io::Error>),
+    /// The other end hung up, such as the USB cable being unplugged.
+    HungUp(String),
+    /// A line that could not be read, dropped.
+    Framing(String, FrameError),
+    /// The task on the other end of a channel has stopped.
+    ChannelClosed,
+}
+
+impl Display for PortError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortError::Missing(path) => write!(fmt, "port {path} not found"),
+            PortError::Open(path, e) => write!(fmt, "unable to open port {path}: {e}"),
+            PortError::Read(path, e) => write!(fmt, "port {path} read failed: {e}"),
+            PortError::Write(path, e) => write!(fmt, "port {path} write failed: {e}"),
+            PortError::HungUp(path) => write!(fmt, "port {path} closed"),
+            PortError::Framing(path, e) => write!(fmt, "port {path}: {e}"),
+            PortError::ChannelClosed => fmt.write_str("port task stopped"),
+        }
+    }
 }
 
-pub async fn uart_read_write<T>(mut port_rx: Receiver<SerialPortInfo>, mut write_channel: Receiver<String>, tx_remote_events: broadcast::Sender<T>)
+impl std::error::Error for PortError {}
+
+impl<T> From<SendError<T>> for PortError {
+    fn from(_: SendError<T>) -> Self {
+        PortError::ChannelClosed
+    }
+}
+
+/// Turns lines read from a port into events. Implementations may keep state
+/// between lines for responses that span more than one.
+pub trait LineDecoder<T> {
+    fn decode_line(&mut self, line: &str) -> Vec<T>;
+
+    /// Called each time `port` is opened, before any of its lines are decoded.
+    fn port_opened(&mut self, _port: &SerialPortInfo) -> Vec<T> { vec![] }
+
+    /// Called when `port` goes away, such as when its USB cable is unplugged, or
+    /// is closed to switch to another.
+    fn port_closed(&mut self, _port: &SerialPortInfo) -> Vec<T> { vec![] }
+
+    /// Called with errors on `port` that the task recovers from, to be reported.
+    fn port_error(&mut self, _port: &SerialPortInfo, _error: &PortError) -> Vec<T> { vec![] }
+}
+
+impl<T, D> LineDecoder<T> for Box<D> where D: LineDecoder<T> + ?Sized {
+    fn decode_line(&mut self, line: &str) -> Vec<T> {
+        (**self).decode_line(line)
+    }
+
+    fn port_opened(&mut self, port: &SerialPortInfo) -> Vec<T> {
+        (**self).port_opened(port)
+    }
+
+    fn port_closed(&mut self, port: &SerialPortInfo) -> Vec<T> {
+        (**self).port_closed(port)
+    }
+
+    fn port_error(&mut self, port: &SerialPortInfo, error: &PortError) -> Vec<T> {
+        (**self).port_error(port, error)
+    }
+}
+
+/// Decodes each line on its own through `FromStr`.
+pub struct FromStrDecoder<T>(PhantomData<T>);
+
+impl<T> Default for FromStrDecoder<T> {
+    fn default() -> Self { Self(PhantomData) }
+}
+
+impl<T> LineDecoder<T> for FromStrDecoder<T> where T: FromStr {
+    fn decode_line(&mut self, line: &str) -> Vec<T> {
+        line.parse().into_iter().collect()
+    }
+}
+
+/// Wait before trying a missing or failed port again, doubling up to the max.
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_MAX: Duration = Duration::from_secs(10);
+
+fn send_events<T>(tx: &broadcast::Sender<T>, events: Vec<T>) {
+    for event in events {
+        if tx.send(event).is_err() {
+            warn!("Failed to send parsed event.")
+        }
+    }
+}
+
+/// Opens the port `configured` names, finding it on USB when it is `auto`.
+async fn open_port(configured: &SerialPortInfo) -> Result<(SerialPortInfo, SerialStream), PortError> {
+    if configured.path == discovery::AUTO_PORT {
+        return discovery::find(configured).await.ok_or_else(|| PortError::Missing(configured.path.clone()));
+    }
+    if !Path::new(&configured.path).exists() {
+        return Err(PortError::Missing(configured.path.clone()));
+    }
+    info!("opening port {}. baud:{}", configured.path, configured.baud);
+    let opened = match configured.firmware {
+        Some(firmware) => discovery::probe(configured, firmware).await.map(|(port, identified)| {
+            if !identified {
+                warn!("{} did not identify as {:?}, using it anyway", configured.path, firmware);
+            }
+            port
+        }),
+        None => tokio_serial::new(configured.path.clone(), configured.baud).open_native_async().map_err(io::Error::from),
+    };
+    match opened {
+        Ok(port) => Ok((configured.clone(), port)),
+        Err(e) => Err(PortError::Open(configured.path.clone(), Arc::new(e))),
+    }
+}
+
+/// Reads lines from the port last sent on `port_rx` and writes `write_channel` to it,
+/// sleeping until one of them or the port has something to do.
+/// A port that is missing or goes away is tried again with a growing wait, and
+/// anything written meanwhile is dropped. Errors are passed to the decoder to
+/// report rather than ending the task. Returns once nothing is left to write for.
+pub async fn uart_read_write<T>(mut port_rx: Receiver<SerialPortInfo>, mut write_channel: Receiver<PortMessage>, tx_remote_events: broadcast::Sender<T>, mut decoder: impl LineDecoder<T>)
     where 
-        T: FromStr,
         T: Clone
 {
-    let mut buf: Vec<u8> = (0..255).collect();
+    let mut buf = [0u8; 256];
     let mut read_buf: Vec<u8> = vec![];
     let mut port_info = None;
+    // the last port stays in use once nothing can send another.
+    let mut port_rx_open = true;
+    let mut backoff = RECONNECT_MIN;
+    // a port that stays missing is reported once, not on every retry.
+    let mut open_error_reported = false;
     loop {
-        if port_info.is_none() {
-            sleep(Duration::from_millis(200)).await;
-            if let Some(next_port) = port_rx.recv().await {
-                port_info = Some(next_port);
-                continue;
+        let Some(configured) = port_info.clone() else {
+            match port_rx.recv().await {
+                Some(next_port) => port_info = Some(next_port),
+                None => return,
             }
-            else {
-                continue;
-            }
-        }
-        let pref = port_info.as_ref().unwrap();
-        if !Path::new(&pref.path).exists() {
-            warn!("Port does not exist: {}", pref.path);
-            port_info = None;
             continue;
-        }
-
-        info!("opening port {}. baud:{}", pref.path, pref.baud);
-        let mut port = tokio_serial::new(pref.path.clone(), pref.baud)
-            .open_native_async().expect("Unable to open serial port.");
-        loop {
-            if let Ok(next_port) = port_rx.try_recv() {
-                port_info = Some(next_port);
-                let _ = port.shutdown().await;
-                break;
-            }
+        };
 
-            let read_result = port.try_read(&mut buf[..]);
-            if let Ok(read) = read_result {
-                for i in 0..read {
-                    read_buf.push(buf[i])
-                }
-                let nl = read_buf.iter().position(|&b| b == b'\n');
-                if let Some(nl_index) = nl {
-                    let line = from_utf8(&read_buf[0..nl_index]);
-                    if let Ok(line) = line {
-                        info!("port read: {}", line);
-                        let event = line.parse::<T>();
-                        if let Ok(event) = event {
-                            let trysend = tx_remote_events.send(event);
-                            if trysend.is_err() {
-                                warn!("Failed to send parsed event.")
-                            }
-                        }
-                    }
-                    read_buf.drain(0..=nl_index);
+        let (pref, mut port) = match open_port(&configured).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("{}, trying again in {:?}", e, backoff);
+                if !open_error_reported {
+                    send_events(&tx_remote_events, decoder.port_error(&configured, &e));
+                    open_error_reported = true;
                 }
-            }
-
-            while let Ok(message) = write_channel.try_recv() {
-                info!("port write: {}", message);
-                port.write(&message.as_bytes()).await.unwrap();
-                if let Some(lchar) = message.chars().last() {
-                    if lchar != '\n' {
-                        port.write(b"\n").await.unwrap();
+                let retry = sleep(backoff);
+                tokio::pin!(retry);
+                backoff = (backoff * 2).min(RECONNECT_MAX);
+                loop {
+                    tokio::select! {
+                        _ = &mut retry => break,
+                        next_port = port_rx.recv(), if port_rx_open => match next_port {
+                            Some(next_port) => {
+                                port_info = Some(next_port);
+                                backoff = RECONNECT_MIN;
+                                open_error_reported = false;
+                                break;
+                            },
+                            None => port_rx_open = false,
+                        },
+                        message = write_channel.recv() => match message {
+                            Some(message) => warn!("port {} closed, dropping write: {}", configured.path, message),
+                            None => return,
+                        },
                     }
                 }
-                port.flush().await.unwrap();
+                continue;
+            },
+        };
+        backoff = RECONNECT_MIN;
+        open_error_reported = false;
+        let mut codec = LineCodec::new(pref.framing);
+        codec.reset(&mut read_buf);
+        send_events(&tx_remote_events, decoder.port_opened(&pref));
+        let error = loop {
+            tokio::select! {
+                next_port = port_rx.recv(), if port_rx_open => match next_port {
+                    Some(next_port) => {
+                        port_info = Some(next_port);
+                        let _ = port.shutdown().await;
+                        break None;
+                    },
+                    None => port_rx_open = false,
+                },
+                read = port.read(&mut buf) => match read {
+                    // a hung up tty reads as end of file.
+                    Ok(0) => break Some(PortError::HungUp(pref.path.clone())),
+                    Ok(read) => {
+                        read_buf.extend_from_slice(&buf[..read]);
+                        loop {
+                            match codec.decode(&mut read_buf) {
+                                Ok(Some(line)) => {
+                                    info!("port read: {}", line);
+                                    send_events(&tx_remote_events, decoder.decode_line(&line));
+                                },
+                                Ok(None) => break,
+                                Err(e) => {
+                                    let e = PortError::Framing(pref.path.clone(), e);
+                                    warn!("{}", e);
+                                    send_events(&tx_remote_events, decoder.port_error(&pref, &e));
+                                },
+                            }
+                        }
+                    },
+                    Err(e) => break Some(PortError::Read(pref.path.clone(), Arc::new(e))),
+                },
+                message = write_channel.recv() => match message {
+                    Some(message) => {
+                        info!("port write: {}", message);
+                        if let Err(e) = write_message(&mut port, &message).await {
+                            break Some(PortError::Write(pref.path.clone(), Arc::new(e)));
+                        }
+                    },
+                    None => return,
+                },
             }
-            yield_now().await;
+        };
+        if let Some(e) = error {
+            warn!("{}", e);
+            send_events(&tx_remote_events, decoder.port_error(&pref, &e));
         }
+        // also when switching ports, the next may not open.
+        send_events(&tx_remote_events, decoder.port_closed(&pref));
     }
 }
 
-pub async fn port_info_from_config(prefix: &str, config: &Config, ch: &Sender<SerialPortInfo>) {
-    let path = format!("{prefix}_PORT");
-    let baud = format!("{prefix}_BAUD");
-    let port_config = SerialPortInfo {
-        path: config.get_string(&path).unwrap(),
-        baud: config.get_int(&baud).unwrap() as u32,
-    };
-    ch.send(port_config).await.expect("unable to send message to serial port config channel");
+async fn write_message(port: &mut SerialStream, message: &PortMessage) -> std::io::Result<()> {
+    port.write_all(&message.to_bytes()).await?;
+    port.flush().await
 }
 
 
 #[allow(dead_code)]
 #[allow(unused_assignments)]
-pub async fn fake_cnc_port(_port_rx: Receiver<SerialPortInfo>, mut write_channel: Receiver<String>, tx_remote_events: broadcast::Sender<CncEvent>) {
+pub async fn fake_cnc_port(_port_rx: Receiver<SerialPortInfo>, mut write_channel: Receiver<PortMessage>, tx_remote_events: broadcast::Sender<CncEvent>) {
     let mut gcode_processing = VecDeque::<String>::new();
     gcode_processing.reserve(10);
-    let mut is_absolute = true;
-    //let mut cnc_position: Point3<f32> = Default::default();
-    let mut target_position: Point3<f32> = Default::default();
+    let mut modal = ModalState::default();
     let mut busy_until: Option<Instant> = None;
-    let max_feed_rate = 300 /* mm/sec */ as f32;
+    let machine = MachineLimits::default();
     loop {
         tokio::time::sleep(Duration::from_millis(30)).await;
         yield_now().await;
         //info!("cnc loop.");
         if gcode_processing.len() < 5 {
-            if let Ok(line) = write_channel.try_recv() {
+            if let Ok(PortMessage::Line(line)) = write_channel.try_recv() {
                 gcode_processing.push_back(line);
                 if gcode_processing.len() < 5 {
                     let _ = tx_remote_events.send(CncEvent::Ok);
@@ -123,9 +323,9 @@ pub async fn fake_cnc_port(_port_rx: Receiver<SerialPortInfo>, mut write_channel
                 }
             }
         }
-        if busy_until.is_some() && Instant::now() <= busy_until.unwrap() {
+        if let Some(until) = busy_until.filter(|&until| Instant::now() <= until) {
             if gcode_processing.len() == 5 {
-                tokio::time::sleep_until(busy_until.unwrap().into()).await;
+                tokio::time::sleep_until(until.into()).await;
             }
             continue;
         } else if busy_until.is_some() {
@@ -138,31 +338,14 @@ pub async fn fake_cnc_port(_port_rx: Receiver<SerialPortInfo>, mut write_channel
         }
         if let Some(code) = gcode_processing.pop_front() {
             busy_until = Some(Instant::now());
-            let code_parts:Vec<_> = gcode::parse(&code).collect();
-            for command in code_parts {
-                match (command.mnemonic(), command.major_number(), command.minor_number()) {
-                    (Mnemonic::General, 91, _) => { is_absolute = true; },
-                    (Mnemonic::General, 90, _) => { is_absolute = false; },
-                    (Mnemonic::General, 0, _)|(Mnemonic::General, 1, _) => {
-                        let p_default = if is_absolute {
-                            Point3::<f32>::default()
-                        } else {
-                            target_position.clone()
-                        };
-                        let mut next_target = target_position.clone();
-
-                        next_target.x = command.value_for('X').unwrap_or(p_default.x);
-                        next_target.y = command.value_for('Y').unwrap_or(p_default.y);
-                        next_target.z = command.value_for('Z').unwrap_or(p_default.z);
-                        let d = next_target.sub(target_position);
-                        let distance = d.mul(d).sum().sqrt();
-                        let travel_time = Duration::from_secs_f32(distance / max_feed_rate);
-                        busy_until = busy_until.unwrap().checked_add(travel_time);
-                        target_position = next_target;
-                        info!("running command: {}; time: {}", code, travel_time.as_secs_f32());
-                    },
-                    _ => { warn!("unknown gcode command: {}", command); },
-                }
+            for command in modal.parse_line(&code).iter().filter(|c| !modal::supports(c)) {
+                warn!("unknown gcode command: {}", command);
+            }
+            for m in modal.apply(&code) {
+                // arcs travel their arc length, at the programmed feed up to the machine's limit.
+                let travel_time = Duration::from_secs_f32(machine.move_time(&m));
+                busy_until = busy_until.and_then(|until| until.checked_add(travel_time));
+                info!("running command: {}; time: {}", code, travel_time.as_secs_f32());
             }
         }
     }
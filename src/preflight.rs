@@ -0,0 +1,159 @@
+use std::{fmt::{self, Display}, io::BufRead, path::Path};
+
+use crate::{estimate::MachineLimits, modal::{self, ModalState, MoveKind}, state::{Point3, TravelLimits}};
+
+/// Problems listed individually, any more are only counted.
+const MAX_MESSAGES: usize = 5;
+
+/// Result of dry running a file through the modal interpreter before it is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preflight {
+    pub lines: usize,
+    pub seconds: f32,
+    /// Work coordinates reached by moves, None when the file makes no moves.
+    pub bounds: Option<(Point3<f32>, Point3<f32>)>,
+    pub warnings: Vec<String>,
+    pub warning_count: usize,
+    /// Problems that stop the job from starting.
+    pub errors: Vec<String>,
+    pub error_count: usize,
+}
+
+impl Display for Preflight {
+    /// `S:<min> <max> <eta seconds> <warnings> <errors>`, with `? ?` for the
+    /// bounds of a file that makes no moves.
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.bounds {
+            Some((min, max)) => write!(fmt, "S:{min} {max}")?,
+            None => write!(fmt, "S:? ?")?,
+        }
+        write!(fmt, " {} {} {}", self.seconds.round() as u32, self.warning_count, self.error_count)
+    }
+}
+
+impl Preflight {
+    fn warn(&mut self, message: String) {
+        if self.warnings.len() < MAX_MESSAGES {
+            self.warnings.push(message);
+        }
+        self.warning_count += 1;
+    }
+
+    fn error(&mut self, message: String) {
+        if self.errors.len() < MAX_MESSAGES {
+            self.errors.push(message);
+        }
+        self.error_count += 1;
+    }
+
+    /// Whether the job may start.
+    pub fn passed(&self) -> bool {
+        self.error_count == 0
+    }
+
+    /// Messages for the remote, the summary last.
+    pub fn messages(&self) -> Vec<String> {
+        let mut messages: Vec<_> = self.errors.iter().map(|e| format!("M: error {e}\n")).collect();
+        messages.extend(self.warnings.iter().map(|w| format!("M: warning {w}\n")));
+        let unlisted = self.error_count + self.warning_count - self.errors.len() - self.warnings.len();
+        if unlisted > 0 {
+            messages.push(format!("M: and {unlisted} more\n"));
+        }
+        messages.push(format!("{self}\n"));
+        messages
+    }
+}
+
+/// Runs every line from `start`, the controller's state when the job begins,
+/// without sending anything. Lines before `first_line` (1 based) only update
+/// the modal state, as they do when resuming.
+pub fn check(path: &Path, first_line: usize, start: ModalState, limits: Option<TravelLimits>, machine: MachineLimits) -> std::io::Result<Preflight> {
+    let mut modal = start;
+    let mut result = Preflight {
+        lines: 0,
+        seconds: 0.0,
+        bounds: None,
+        warnings: vec![],
+        warning_count: 0,
+        errors: vec![],
+        error_count: 0,
+    };
+    let mut feed_checked = false;
+    for line in std::io::BufReader::new(std::fs::File::open(path)?).lines() {
+        let line = line?;
+        result.lines += 1;
+        let line_number = result.lines;
+        if line_number < first_line {
+            modal.apply(&line);
+            continue;
+        }
+        for command in modal.parse_line(&line).iter().filter(|c| !modal::supports(c)) {
+            result.warn(format!("line {line_number}: unsupported {command}"));
+        }
+        let moves = modal.apply(&line);
+        let work_offset = modal.machine_position.sub(modal.work_position());
+        for m in moves {
+            if m.kind != MoveKind::Rapid && !feed_checked {
+                feed_checked = true;
+                if m.feed_rate.is_none() {
+                    result.error(format!("line {line_number}: first feed move has no F"));
+                }
+            }
+            result.seconds += machine.move_time(&m);
+
+            let points = m.points();
+            if let Some(outside) = limits.and_then(|limits| points.iter().find(|p| !limits.contains(**p))) {
+                result.error(format!("line {line_number}: {outside} beyond travel"));
+            }
+            for p in points {
+                let p = p.sub(work_offset);
+                result.bounds = Some(match result.bounds {
+                    None => (p, p),
+                    Some((min, max)) => (min.apply_other(p, f32::min), max.apply_other(p, f32::max)),
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `name` keeps the temp file apart from other tests running at the same time.
+    fn check_lines(name: &str, lines: &str, limits: Option<TravelLimits>) -> Preflight {
+        let path = std::env::temp_dir().join(format!("preflight_{}_{}.nc", std::process::id(), name));
+        std::fs::write(&path, lines).expect("write test file");
+        let result = check(&path, 1, ModalState::default(), limits, MachineLimits::default()).expect("check");
+        std::fs::remove_file(&path).expect("remove test file");
+        result
+    }
+
+    #[test]
+    fn summary_of_a_clean_file() {
+        let result = check_lines("clean", "G21 G90\nG0 Z5\nG1 Z-1 F600\nG2 X20 Y0 I10 J0\nG0 Z5\n", None);
+        assert!(result.passed());
+        assert_eq!(result.warning_count, 0);
+        assert_eq!(result.lines, 5);
+        let (min, max) = result.bounds.expect("moves");
+        assert_eq!(min.z, -1.0);
+        assert_eq!(max, Point3::new(20.0, 10.0, 5.0));
+        assert!(result.to_string().starts_with("S:X0 Y0 Z-1 X20 Y10 Z5 "));
+    }
+
+    #[test]
+    fn problems() {
+        let limits = TravelLimits { min: Point3::new(0.0, 0.0, -50.0), max: Point3::new(100.0, 100.0, 0.0) };
+        let result = check_lines("probe", "G38.2 Z-10 F100\nG1 X10\n", Some(limits));
+        assert!(result.passed());
+        assert_eq!(result.warning_count, 1);
+
+        let result = check_lines("beyond_travel", "G1 X10\nG0 X150\nG0 Z10\n", Some(limits));
+        assert!(!result.passed());
+        assert_eq!(result.error_count, 3);
+        assert_eq!(result.errors[0], "line 1: first feed move has no F");
+        assert_eq!(result.errors[1], "line 2: X150 Y0 Z0 beyond travel");
+        assert_eq!(result.messages().last().map(String::as_str), Some("S:X10 Y0 Z0 X150 Y0 Z10 1 0 3\n"));
+    }
+}
@@ -0,0 +1,408 @@
+use std::{fmt::{self, Display}, path::PathBuf, str::FromStr, time::Duration};
+
+use config::{builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment, File, Map};
+use log::warn;
+
+use crate::{
+    brain::BrainConfig,
+    discovery::UsbId,
+    estimate::MachineLimits,
+    firmware::Firmware,
+    framing::{Framing, Utf8Policy},
+    jog::JogAcceleration,
+    port_io::SerialPortInfo,
+    state::{Point3, TravelLimits},
+    streaming::FlowControl,
+    template::Template,
+};
+
+/// Read when `CNC_CONFIG` does not name another file. Missing is fine, the
+/// defaults and environment variables are used on their own.
+const DEFAULT_CONFIG_FILE: &str = "/etc/rpi_cnc_remote.toml";
+
+/// Where `<name>.toml` machine profiles live when `CNC_PROFILE_DIR` is not set.
+const DEFAULT_PROFILE_DIR: &str = "/etc/rpi_cnc_remote/profiles";
+
+/// A setting that could not be used, reported at startup.
+#[derive(Debug)]
+pub enum SettingsError {
+    Invalid { key: &'static str, value: String, expected: &'static str },
+    Config(ConfigError),
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::Invalid { key, value, expected } => write!(fmt, "{key} is {value:?}, expected {expected}"),
+            SettingsError::Config(e) => write!(fmt, "{e}"),
+        }
+    }
+}
+
+impl From<ConfigError> for SettingsError {
+    fn from(e: ConfigError) -> Self {
+        SettingsError::Config(e)
+    }
+}
+
+/// Defaults, then the TOML file, then `CNC_*` and `XBEE_*` environment variables.
+/// Keys are the same everywhere and not case sensitive, `cnc_max_feed_rate = 250`
+/// in the file or `CNC_MAX_FEED_RATE=250` in the environment. The older
+/// `CNC_XBEE_PORT` style names for the XBee settings still work, below `XBEE_PORT`.
+pub fn builder() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+    Sources::system().builder(None)
+}
+
+fn profile_dir(config: &Config) -> PathBuf {
+    PathBuf::from(config.get_string("CNC_PROFILE_DIR").unwrap_or_else(|_| DEFAULT_PROFILE_DIR.to_owned()))
+}
+
+/// Names of the profiles in the profile directory, sorted.
+pub fn profiles() -> Result<Vec<String>, SettingsError> {
+    let config = builder()?.build()?;
+    let mut names: Vec<_> = std::fs::read_dir(profile_dir(&config)).into_iter().flatten().flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "toml"))
+        .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Loads the configuration for `profile`, or for `CNC_PROFILE` when None. With
+/// neither there is no profile and only the main file and environment apply.
+pub fn load(profile: Option<&str>) -> Result<(Config, Option<String>), SettingsError> {
+    Sources::system().load(profile)
+}
+
+/// Where settings are read from besides the defaults. The process's own
+/// environment and `DEFAULT_CONFIG_FILE`, except in tests.
+struct Sources {
+    /// Environment variables, None for the process's own.
+    vars: Option<Map<String, String>>,
+    /// Read when `CNC_CONFIG` does not name another file, if it exists.
+    default_file: Option<PathBuf>,
+}
+
+impl Sources {
+    fn system() -> Self {
+        Self { vars: None, default_file: Some(PathBuf::from(DEFAULT_CONFIG_FILE)) }
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        match &self.vars {
+            Some(vars) => vars.get(key).cloned(),
+            None => std::env::var(key).ok(),
+        }
+    }
+
+    fn environment(&self, prefix: &str) -> Environment {
+        Environment::with_prefix(prefix).try_parsing(true).source(self.vars.clone())
+    }
+
+    /// As `builder`, with a machine profile's file layered between the main file
+    /// and the environment.
+    fn builder(&self, profile: Option<PathBuf>) -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+        let mut builder = Config::builder()
+            .set_default("XBEE_PORT", "/dev/ttyAMA0")?
+            .set_default("XBEE_BAUD", "9600")?
+            .set_default("CNC_PORT", "/dev/ttyUSB0")?
+            .set_default("CNC_BAUD", "115200")?
+            .set_default("CNC_FIRMWARE", "marlin")?
+            .set_default("CNC_LINE_NUMBERS", false)?
+            .set_default("CNC_PRE_JOB", "G90")?
+            .set_default("CNC_POST_JOB", "G90|G21")?
+            .set_default("CNC_JOG_ACCEL_THRESHOLD", 5.0)?
+            .set_default("CNC_JOG_ACCEL_GAIN", 0.2)?
+            .set_default("CNC_JOG_ACCEL_MAX", 10.0)?
+            .set_default("CNC_MAX_FEED_RATE", 300.0)?
+            .set_default("CNC_JOG_CEILING_TIME", 0.1)?
+            .set_default("CNC_JOG_STEP", 0.1)?
+            .set_default("CNC_LOOP_INTERVAL_MS", 20)?;
+        match self.var("CNC_CONFIG") {
+            // a file named explicitly has to be there.
+            Some(file) => builder = builder.add_source(File::with_name(&file)),
+            None => if let Some(file) = &self.default_file {
+                builder = builder.add_source(File::from(file.as_path()).required(false));
+            },
+        }
+        if let Some(path) = profile {
+            builder = builder.add_source(File::from(path));
+        }
+        Ok(builder
+            // `CNC_XBEE_PORT` to `xbee_port`, as before the keys kept their prefix.
+            .add_source(self.environment("CNC"))
+            .add_source(self.environment("CNC").keep_prefix(true))
+            .add_source(self.environment("XBEE").keep_prefix(true)))
+    }
+
+    fn load(&self, profile: Option<&str>) -> Result<(Config, Option<String>), SettingsError> {
+        let base = self.builder(None)?.build()?;
+        let Some(name) = profile.map(str::to_owned).or_else(|| base.get_string("CNC_PROFILE").ok()) else {
+            return Ok((base, None));
+        };
+        // a name is a file in the profile directory, not a path to anywhere else.
+        if name.is_empty() || name.contains(['/', '\\', '.']) {
+            return Err(SettingsError::Invalid { key: "CNC_PROFILE", value: name, expected: "a profile name" });
+        }
+        let path = profile_dir(&base).join(format!("{name}.toml"));
+        if !path.is_file() {
+            return Err(SettingsError::Invalid { key: "CNC_PROFILE", value: name, expected: "a profile in the profile directory" });
+        }
+        Ok((self.builder(Some(path))?.build()?, Some(name)))
+    }
+}
+
+/// Settings read for each serial link, `XBEE_*` or `CNC_*`.
+struct PortKeys {
+    port: &'static str,
+    baud: &'static str,
+    /// `lf`, `cr` or a single character lines end with.
+    delimiter: &'static str,
+    max_line_length: &'static str,
+    strip_cr: &'static str,
+    /// `lossy` or `strict`.
+    utf8: &'static str,
+}
+
+const XBEE_KEYS: PortKeys = PortKeys {
+    port: "XBEE_PORT",
+    baud: "XBEE_BAUD",
+    delimiter: "XBEE_LINE_DELIMITER",
+    max_line_length: "XBEE_MAX_LINE_LENGTH",
+    strip_cr: "XBEE_STRIP_CR",
+    utf8: "XBEE_UTF8",
+};
+
+const CNC_KEYS: PortKeys = PortKeys {
+    port: "CNC_PORT",
+    baud: "CNC_BAUD",
+    delimiter: "CNC_LINE_DELIMITER",
+    max_line_length: "CNC_MAX_LINE_LENGTH",
+    strip_cr: "CNC_STRIP_CR",
+    utf8: "CNC_UTF8",
+};
+
+fn framing(config: &Config, keys: &PortKeys) -> Result<Framing, SettingsError> {
+    let defaults = Framing::default();
+    let delimiter = match optional::<String>(config, keys.delimiter, "lf, cr or a single character")?.as_deref() {
+        None => defaults.delimiter,
+        Some("lf") => b'\n',
+        Some("cr") => b'\r',
+        Some(c) if c.len() == 1 => c.as_bytes()[0],
+        Some(c) => return Err(SettingsError::Invalid { key: keys.delimiter, value: c.to_owned(), expected: "lf, cr or a single character" }),
+    };
+    let max_length = optional::<usize>(config, keys.max_line_length, "a whole number")?.unwrap_or(defaults.max_length);
+    if max_length == 0 {
+        return Err(SettingsError::Invalid { key: keys.max_line_length, value: max_length.to_string(), expected: "a whole number above 0" });
+    }
+    let utf8 = match optional::<String>(config, keys.utf8, "lossy or strict")?.map(|p| p.to_lowercase()).as_deref() {
+        None => defaults.utf8,
+        Some("lossy") => Utf8Policy::Lossy,
+        Some("strict") => Utf8Policy::Strict,
+        Some(p) => return Err(SettingsError::Invalid { key: keys.utf8, value: p.to_owned(), expected: "lossy or strict" }),
+    };
+    Ok(Framing {
+        delimiter,
+        max_length,
+        strip_cr: optional(config, keys.strip_cr, "true or false")?.unwrap_or(defaults.strip_cr),
+        utf8,
+    })
+}
+
+fn port_info(config: &Config, keys: &PortKeys, firmware: Option<Firmware>, usb_ids: Vec<UsbId>) -> Result<SerialPortInfo, SettingsError> {
+    let baud: u32 = parse(config, keys.baud, "a baud rate")?;
+    if baud == 0 {
+        return Err(SettingsError::Invalid { key: keys.baud, value: baud.to_string(), expected: "a baud rate" });
+    }
+    Ok(SerialPortInfo { path: config.get_string(keys.port)?, baud, firmware, usb_ids, framing: framing(config, keys)? })
+}
+
+fn parse<T: FromStr>(config: &Config, key: &'static str, expected: &'static str) -> Result<T, SettingsError> {
+    let value = config.get_string(key)?;
+    value.parse().map_err(|_| SettingsError::Invalid { key, value, expected })
+}
+
+fn optional<T: FromStr>(config: &Config, key: &'static str, expected: &'static str) -> Result<Option<T>, SettingsError> {
+    match config.get_string(key) {
+        Ok(_) => parse(config, key, expected).map(Some),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn positive(config: &Config, key: &'static str) -> Result<f32, SettingsError> {
+    let v: f32 = parse(config, key, "a number above 0")?;
+    if v > 0.0 && v.is_finite() {
+        Ok(v)
+    } else {
+        Err(SettingsError::Invalid { key, value: v.to_string(), expected: "a number above 0" })
+    }
+}
+
+fn positive_axes(config: &Config, key: &'static str) -> Result<Option<Point3<f32>>, SettingsError> {
+    const EXPECTED: &str = "X.. Y.. Z.. above 0";
+    let Some(p) = optional::<Point3<f32>>(config, key, EXPECTED)? else { return Ok(None); };
+    if [p.x, p.y, p.z].iter().all(|v| *v > 0.0) {
+        Ok(Some(p))
+    } else {
+        Err(SettingsError::Invalid { key, value: p.to_string(), expected: EXPECTED })
+    }
+}
+
+/// Everything the brain needs, checked so that bad values stop startup with
+/// the offending key rather than misbehaving later.
+pub fn brain_config(config: &Config) -> Result<BrainConfig, SettingsError> {
+    let firmware: Firmware = parse(config, "CNC_FIRMWARE", "marlin or grbl")?;
+    let mut line_numbers: bool = parse(config, "CNC_LINE_NUMBERS", "true or false")?;
+    if line_numbers && !firmware.supports_line_numbers() {
+        warn!("CNC_LINE_NUMBERS ignored, {:?} does not check line numbers", firmware);
+        line_numbers = false;
+    }
+    let mut flow_control = match optional::<FlowControl>(config, "CNC_FLOW_CONTROL", "lines or chars")? {
+        Some(flow) => firmware.sized_flow_control(flow),
+        None => firmware.default_flow_control(),
+    };
+    if let Some(size) = optional::<usize>(config, "CNC_BUFFER_SIZE", "a whole number")? {
+        if size == 0 {
+            return Err(SettingsError::Invalid { key: "CNC_BUFFER_SIZE", value: size.to_string(), expected: "a whole number above 0" });
+        }
+        flow_control = flow_control.with_size(size);
+    }
+
+    let jog_acceleration = JogAcceleration {
+        threshold: parse(config, "CNC_JOG_ACCEL_THRESHOLD", "a number")?,
+        gain: parse(config, "CNC_JOG_ACCEL_GAIN", "a number")?,
+        max_multiplier: parse(config, "CNC_JOG_ACCEL_MAX", "a number")?,
+    };
+    if jog_acceleration.gain < 0.0 {
+        return Err(SettingsError::Invalid { key: "CNC_JOG_ACCEL_GAIN", value: jog_acceleration.gain.to_string(), expected: "0 or more" });
+    }
+    if jog_acceleration.max_multiplier < 1.0 {
+        return Err(SettingsError::Invalid { key: "CNC_JOG_ACCEL_MAX", value: jog_acceleration.max_multiplier.to_string(), expected: "1 or more" });
+    }
+
+    let travel_min = optional::<Point3<f32>>(config, "CNC_TRAVEL_MIN", "X.. Y.. Z..")?;
+    let travel_max = optional::<Point3<f32>>(config, "CNC_TRAVEL_MAX", "X.. Y.. Z..")?;
+    let travel_limits = match (travel_min, travel_max) {
+        (Some(min), Some(max)) if min.x < max.x && min.y < max.y && min.z < max.z => Some(TravelLimits { min, max }),
+        (Some(_), Some(max)) => return Err(SettingsError::Invalid { key: "CNC_TRAVEL_MAX", value: max.to_string(), expected: "above CNC_TRAVEL_MIN on every axis" }),
+        (None, None) => {
+            warn!("CNC_TRAVEL_MIN and CNC_TRAVEL_MAX not set, jogs and jobs are not kept within machine travel");
+            None
+        },
+        (Some(min), None) => return Err(SettingsError::Invalid { key: "CNC_TRAVEL_MIN", value: min.to_string(), expected: "to be set along with CNC_TRAVEL_MAX" }),
+        (None, Some(max)) => return Err(SettingsError::Invalid { key: "CNC_TRAVEL_MAX", value: max.to_string(), expected: "to be set along with CNC_TRAVEL_MIN" }),
+    };
+
+    let max_feed_rate = positive(config, "CNC_MAX_FEED_RATE")?;
+    let defaults = MachineLimits::default();
+    let machine = MachineLimits {
+        feed: positive_axes(config, "CNC_AXIS_FEED")?.unwrap_or(Point3::new_uniform(max_feed_rate)),
+        acceleration: positive_axes(config, "CNC_AXIS_ACCELERATION")?.unwrap_or(defaults.acceleration),
+    };
+    let usb_ids = match config.get_string("CNC_USB_IDS") {
+        Ok(ids) => ids.split([',', ' ']).filter(|id| !id.is_empty())
+            .map(|id| id.parse().map_err(|_| SettingsError::Invalid { key: "CNC_USB_IDS", value: id.to_owned(), expected: "vendor:product ids in hex" }))
+            .collect::<Result<Vec<UsbId>, _>>()?,
+        Err(ConfigError::NotFound(_)) => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    let loop_interval: u64 = parse(config, "CNC_LOOP_INTERVAL_MS", "a whole number of milliseconds")?;
+    if !(1..=1000).contains(&loop_interval) {
+        return Err(SettingsError::Invalid { key: "CNC_LOOP_INTERVAL_MS", value: loop_interval.to_string(), expected: "1 to 1000" });
+    }
+
+    Ok(BrainConfig {
+        profile: None,
+        xbee_port: port_info(config, &XBEE_KEYS, None, vec![])?,
+        cnc_port: port_info(config, &CNC_KEYS, Some(firmware), usb_ids)?,
+        firmware,
+        line_numbers,
+        flow_control,
+        pre_job: parse::<Template>(config, "CNC_PRE_JOB", "gcode lines or @file")?,
+        post_job: parse::<Template>(config, "CNC_POST_JOB", "gcode lines or @file")?,
+        jog_acceleration,
+        travel_limits,
+        max_feed_rate,
+        jog_ceiling_time: positive(config, "CNC_JOG_CEILING_TIME")?,
+        jog_step: positive(config, "CNC_JOG_STEP")?,
+        machine,
+        loop_interval: Duration::from_millis(loop_interval),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    /// Only `vars` for the environment and no default file, whatever the machine running the tests has.
+    fn isolated(vars: &[(&str, &str)]) -> Sources {
+        let vars = vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        Sources { vars: Some(vars), default_file: None }
+    }
+
+    fn from_toml(toml: &str) -> Result<BrainConfig, SettingsError> {
+        let config = isolated(&[]).builder(None).expect("defaults")
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .expect("config");
+        brain_config(&config)
+    }
+
+    #[test]
+    fn toml_overrides_defaults() {
+        let brain = from_toml("cnc_firmware = \"grbl\"\ncnc_max_feed_rate = 250\ncnc_axis_acceleration = \"X800 Y800 Z100\"\n").expect("valid");
+        assert_eq!(brain.firmware, Firmware::Grbl);
+        assert_eq!(brain.flow_control, FlowControl::CharacterCounting(128));
+        assert_eq!(brain.max_feed_rate, 250.0);
+        assert_eq!(brain.machine.feed, Point3::new_uniform(250.0));
+        assert_eq!(brain.machine.acceleration, Point3::new(800.0, 800.0, 100.0));
+        assert_eq!(brain.loop_interval, Duration::from_millis(20));
+        assert_eq!(brain.cnc_port.framing, Framing::default());
+    }
+
+    #[test]
+    fn flow_control_sized_for_firmware_and_policy() {
+        let flow = |toml: &str| from_toml(toml).expect("valid").flow_control;
+        assert_eq!(flow("cnc_firmware = \"grbl\"\ncnc_flow_control = \"lines\""), FlowControl::LineCounting(1));
+        assert_eq!(flow("cnc_firmware = \"marlin\"\ncnc_flow_control = \"chars\""), FlowControl::CharacterCounting(128));
+        assert_eq!(flow("cnc_firmware = \"marlin\"\ncnc_flow_control = \"chars\"\ncnc_buffer_size = 64"), FlowControl::CharacterCounting(64));
+    }
+
+    #[test]
+    fn xbee_settings_from_the_environment() {
+        let sources = isolated(&[("CNC_XBEE_PORT", "/dev/ttyS5"), ("XBEE_MAX_LINE_LENGTH", "80")]);
+        let brain = brain_config(&sources.builder(None).expect("defaults").build().expect("config")).expect("valid");
+        assert_eq!(brain.xbee_port.path, "/dev/ttyS5");
+        assert_eq!(brain.xbee_port.framing.max_length, 80);
+    }
+
+    #[test]
+    fn profile_names_are_files_in_the_profile_directory() {
+        let dir = std::env::temp_dir().join(format!("rpi_cnc_remote_profiles_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create profile dir");
+        std::fs::write(dir.join("router.toml"), "cnc_firmware = \"grbl\"\n").expect("write profile");
+        let sources = isolated(&[("CNC_PROFILE_DIR", dir.to_str().expect("utf-8 temp dir"))]);
+        assert!(matches!(sources.load(Some("../etc/passwd")), Err(SettingsError::Invalid { key: "CNC_PROFILE", .. })));
+        assert!(matches!(sources.load(Some("no_such_profile")), Err(SettingsError::Invalid { key: "CNC_PROFILE", .. })));
+        let (config, name) = sources.load(Some("router")).expect("profile");
+        std::fs::remove_dir_all(&dir).expect("remove profile dir");
+        assert_eq!(name.as_deref(), Some("router"));
+        assert_eq!(brain_config(&config).expect("valid").firmware, Firmware::Grbl);
+    }
+
+    #[test]
+    fn bad_values_name_the_key() {
+        let error = |toml: &str| from_toml(toml).err().expect("invalid").to_string();
+        assert_eq!(error("cnc_firmware = \"smoothie\""), "CNC_FIRMWARE is \"smoothie\", expected marlin or grbl");
+        assert_eq!(error("cnc_max_feed_rate = -1"), "CNC_MAX_FEED_RATE is \"-1\", expected a number above 0");
+        assert!(error("cnc_axis_feed = \"X100 Y0 Z10\"").starts_with("CNC_AXIS_FEED"));
+        assert!(error("cnc_travel_min = \"X0 Y0 Z0\"").starts_with("CNC_TRAVEL_MIN"));
+        assert!(error("cnc_loop_interval_ms = 0").starts_with("CNC_LOOP_INTERVAL_MS"));
+        assert!(error("xbee_line_delimiter = \"crlf\"").starts_with("XBEE_LINE_DELIMITER"));
+        assert!(error("cnc_utf8 = \"latin1\"").starts_with("CNC_UTF8"));
+        assert_eq!(error("cnc_usb_ids = \"1a86:7523, ftdi\""), "CNC_USB_IDS is \"ftdi\", expected vendor:product ids in hex");
+    }
+}
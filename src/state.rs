@@ -1,7 +1,10 @@
-use std::{fmt::{self, Debug, Display}, str::FromStr, time::{Duration, Instant}};
+use std::{collections::BTreeMap, fmt::{self, Debug, Display}, str::FromStr, time::{Duration, Instant}};
 use log::warn;
 
-#[derive(Clone, Copy, PartialEq)]
+use crate::jog::JogCommand;
+use crate::port_io::PortError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Point3<T> {
     pub x: T,
     pub y: T,
@@ -102,6 +105,46 @@ impl<T> FromStr for Point3<T> where T: FromStr, T: Default {
     }
 }
 
+/// Machine travel in machine coordinates and mm, from `CNC_TRAVEL_MIN` and `CNC_TRAVEL_MAX`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TravelLimits {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl TravelLimits {
+    pub fn contains(&self, p: Point3<f32>) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+
+    /// Shortens a relative jog from `from` so it stops at the travel limits. An
+    /// axis already outside them may move back in but not further out.
+    pub fn limit_jog(&self, from: Point3<f32>, jog: Point3<f32>) -> Point3<f32> {
+        let limit = |v: f32, from: f32, min: f32, max: f32| {
+            if v > 0.0 {
+                v.min((max - from).max(0.0))
+            } else {
+                v.max((min - from).min(0.0))
+            }
+        };
+        Point3::new(
+            limit(jog.x, from.x, self.min.x, self.max.x),
+            limit(jog.y, from.y, self.min.y, self.max.y),
+            limit(jog.z, from.z, self.min.z, self.max.z),
+        )
+    }
+
+    /// Names of the limits (`x_max`, ...) `jog` was cut short at by `limit_jog`.
+    pub fn pinned(jog: Point3<f32>, limited: Point3<f32>) -> Vec<String> {
+        [("x", jog.x, limited.x), ("y", jog.y, limited.y), ("z", jog.z, limited.z)].iter()
+            .filter(|(_, v, limited)| v != limited)
+            .map(|(axis, v, _)| format!("{axis}_{}", if *v > 0.0 { "max" } else { "min" }))
+            .collect()
+    }
+}
+
 pub trait DelayUpdates {
     fn needs_update(&self) -> bool;
     fn update(&mut self);
@@ -112,6 +155,7 @@ pub trait TrackCurrentPrevious<T> {
     fn current(&self) -> &T;
     fn previous(&self) -> &T;
     fn current_mut(&mut self) -> &mut T;
+    #[allow(dead_code)]
     fn previous_mut(&mut self) -> &mut T;
 }
 
@@ -156,7 +200,7 @@ pub struct DebounceTracker {
 }
 
 impl DebounceTracker {
-    fn new(debounce: Duration) -> Self {
+    pub fn new(debounce: Duration) -> Self {
         Self { update_time: Instant::now(), debounce }
     }
 }
@@ -191,8 +235,8 @@ impl<T> DebounceDiffTracker<T> where T : Clone {
 }
 
 impl<T> TrackCurrentPrevious<T> for DebounceDiffTracker<T> {
-    fn current(&self) -> &T { &self.diff.current() }
-    fn previous(&self) -> &T { &self.diff.previous() }
+    fn current(&self) -> &T { self.diff.current() }
+    fn previous(&self) -> &T { self.diff.previous() }
     fn current_mut(&mut self) -> &mut T { self.diff.current_mut() }
     fn previous_mut(&mut self) -> &mut T { self.diff.previous_mut() }
 }
@@ -201,13 +245,11 @@ impl<T> DelayUpdates for DebounceDiffTracker<T> where T : Clone, T : PartialEq {
     fn needs_update(&self) -> bool { self.diff.needs_update() && self.debounce.needs_update() }
     fn update(&mut self) { self.diff.update(); self.debounce.update(); }
     fn update_check(&mut self) -> bool {
-        if self.diff.needs_update() {
-            if self.debounce.update_check() {
-                self.diff.update();
-                return true;
-            }
+        if self.diff.needs_update() && self.debounce.update_check() {
+            self.diff.update();
+            return true;
         }
-        return false;
+        false
     }
 }
 
@@ -217,32 +259,87 @@ pub enum RemoteEvent {
     DialXYZEvent(Point3<i64>),
     SDList((String, usize)),
     SDLoadFile(String),
+    ResumeFile((String, usize)), // path, 1 based line to resume from
     RunGCode(String),
+    JobPause,
+    JobResume,
+    JobAbort,
+    JobStart, // operator confirmed a job after its pre-flight summary
+    JogSetting(JogCommand),
+    ListProfiles,
+    SelectProfile(String),
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct ParseCNCEventError;
-impl FromStr for CncEvent {
-    type Err = ParseCNCEventError;
-    fn from_str(input: &str) -> Result<Self, Self::Err> {
-        if input.to_lowercase().contains("ok") {
-            Ok(CncEvent::Ok)
-        }
-        else {
-            warn!("unrecognized input: {}", input);
-            // todo
-            Ok(CncEvent::Unknown)
+/// Position as reported by M114.
+/// `X:0.00 Y:127.00 Z:145.00 E:0.00 Count X: 0 Y:10160 Z:116000`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PositionReport {
+    pub position: Point3<f32>,
+    pub extruder: Option<f32>,
+    pub counts: Option<Point3<i64>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndStopState {
+    Open,
+    Triggered,
+}
+
+impl Display for EndStopState {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EndStopState::Open => fmt.write_str("open"),
+            EndStopState::Triggered => fmt.write_str("TRIGGERED"),
         }
     }
 }
 
+/// Switch name (`x_min`, `z_probe`, ...) to state, as reported by M119.
+pub type EndStops = BTreeMap<String, EndStopState>;
+
+/// Controller state as reported by GRBL's `<Idle|...>` status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineState {
+    Idle,
+    Run,
+    Hold,
+    Jog,
+    Alarm,
+    Door,
+    Check,
+    Home,
+    Sleep,
+    Unknown,
+}
+
+/// Real-time status report. Positions missing from the report are left as None.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MachineStatus {
+    pub state: MachineState,
+    pub machine_position: Option<Point3<f32>>,
+    pub work_position: Option<Point3<f32>>,
+    pub feed_rate: Option<f32>,
+    pub spindle_speed: Option<f32>,
+}
+
+/// Responses from the CNC controller, independent of the firmware dialect that produced them.
 #[allow(dead_code)]
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub enum CncEvent {
     Unknown,
     Ok,
-    PositionReport(String), // from M114\n // X:0.00 Y:127.00 Z:145.00 E:0.00 Count X: 0 Y:10160 Z:116000\n // ok \n
-    EndStopStates(String), // from M119\n // x_min: open\n y_min: open\nz_min: TRIGGERED\nz_probe: open\nfilament: open\n
+    PositionReport(PositionReport), // from M114
+    EndStopStates(EndStops), // from M119
+    Status(MachineStatus), // grbl <Idle|MPos:...>
+    Error(String),
+    Resend(u32), // firmware asks for everything from this line number on
+    Busy, // marlin echo:busy: processing, the line is still running
+    Start, // controller (re)started, anything in flight is lost
+    Alarm(u32),
+    Message(String),
+    Connected(String), // port opened, with the path it was found at
+    Disconnected, // port went away, such as the USB cable being unplugged
+    PortError(PortError), // recovered from, reported to the remote
 }
 
 #[allow(dead_code)]
@@ -252,3 +349,23 @@ pub enum AppMode {
     Jog,
     RunningFile,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jogs_stop_at_travel_limits() {
+        let limits = TravelLimits { min: Point3::new(0.0, 0.0, -50.0), max: Point3::new(100.0, 100.0, 0.0) };
+        let from = Point3::new(95.0, 2.0, -1.0);
+        let jog = Point3::new(10.0, -5.0, -1.0);
+        let limited = limits.limit_jog(from, jog);
+        assert_eq!(limited, Point3::new(5.0, -2.0, -1.0));
+        assert_eq!(TravelLimits::pinned(jog, limited), ["x_max", "y_min"]);
+
+        // outside the envelope, only moves back towards it are allowed.
+        let from = Point3::new(120.0, 50.0, 0.0);
+        assert_eq!(limits.limit_jog(from, Point3::new(1.0, 0.0, 0.0)), Point3::default());
+        assert_eq!(limits.limit_jog(from, Point3::new(-1.0, 0.0, 0.0)), Point3::new(-1.0, 0.0, 0.0));
+    }
+}
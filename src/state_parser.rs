@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use crate::state::RemoteEvent;
+use crate::state::{EndStopState, Point3, PositionReport, RemoteEvent};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseRemoteEventError {
@@ -10,12 +10,15 @@ pub enum ParseRemoteEventError {
 impl FromStr for RemoteEvent {
     type Err = ParseRemoteEventError;
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        if input.len() <= 4 {
+        // lines may or may not still carry their line ending.
+        let input = input.trim_end_matches(['\r', '\n']);
+        // radio noise may be anything, only split where there is a character boundary.
+        let (id, data_part) = input.split_at_checked(2).ok_or(ParseRemoteEventError::BadStartingId)?;
+        if data_part.is_empty() {
             return Err(ParseRemoteEventError::BadStartingId);
         }
-        let data_part = &input[2..input.len()-1];
-        match &input[..2] {
-            "W:" => data_part.parse().map(|p| RemoteEvent::DialXYZEvent(p)).map_err(|_| ParseRemoteEventError::ParseError),
+        match id {
+            "W:" => data_part.parse().map(RemoteEvent::DialXYZEvent).map_err(|_| ParseRemoteEventError::ParseError),
             "L:" => {
                 if let Some((skip, dir)) = data_part.split_once(" ") {
                     if let Ok(skip) = skip.parse() {
@@ -30,15 +33,99 @@ impl FromStr for RemoteEvent {
                 }
             },
             "F:" => Ok(RemoteEvent::SDLoadFile(data_part.to_string())),
+            "N:" => {
+                let (line, path) = data_part.split_once(' ').ok_or(ParseRemoteEventError::ParseError)?;
+                let line = line.parse().map_err(|_| ParseRemoteEventError::ParseError)?;
+                Ok(RemoteEvent::ResumeFile((path.to_string(), line)))
+            },
             "G:" => Ok(RemoteEvent::RunGCode(data_part.to_string())),
+            "J:" => match data_part {
+                "pause" => Ok(RemoteEvent::JobPause),
+                "resume" => Ok(RemoteEvent::JobResume),
+                "abort" => Ok(RemoteEvent::JobAbort),
+                "start" => Ok(RemoteEvent::JobStart),
+                _ => Err(ParseRemoteEventError::ParseError),
+            },
+            "D:" => data_part.parse().map(RemoteEvent::JogSetting).map_err(|_| ParseRemoteEventError::ParseError),
+            "C:" => match data_part.trim() {
+                "?" => Ok(RemoteEvent::ListProfiles),
+                name => Ok(RemoteEvent::SelectProfile(name.to_string())),
+            },
             _ => Err(ParseRemoteEventError::BadStartingId),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsePositionReportError;
+
+/// Splits `X:1.0 Y: 2` style text into axis/value pairs. Marlin pads the step
+/// counts with a space after the colon, so an empty value takes the next token.
+fn axis_values<T: FromStr>(input: &str) -> Result<Vec<(&str, T)>, ParsePositionReportError> {
+    let mut values = vec![];
+    let mut tokens = input.split_whitespace();
+    while let Some(token) = tokens.next() {
+        let (axis, value) = token.split_once(':').ok_or(ParsePositionReportError)?;
+        let value = if value.is_empty() { tokens.next().ok_or(ParsePositionReportError)? } else { value };
+        values.push((axis, value.parse().map_err(|_| ParsePositionReportError)?));
+    }
+    Ok(values)
+}
+
+impl FromStr for PositionReport {
+    type Err = ParsePositionReportError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (logical, counts) = match input.split_once("Count") {
+            Some((logical, counts)) => (logical, Some(counts)),
+            None => (input, None),
+        };
+        let mut report = PositionReport::default();
+        let mut xyz_set = [false, false, false];
+        for (axis, value) in axis_values::<f32>(logical)? {
+            match axis {
+                "X" => { report.position.x = value; xyz_set[0] = true; },
+                "Y" => { report.position.y = value; xyz_set[1] = true; },
+                "Z" => { report.position.z = value; xyz_set[2] = true; },
+                "E" => { report.extruder = Some(value); },
+                _ => {},
+            }
+        }
+        if !xyz_set.iter().all(|&v| v) {
+            return Err(ParsePositionReportError);
+        }
+        if let Some(counts) = counts {
+            let mut steps = Point3::<i64>::default();
+            for (axis, value) in axis_values::<i64>(counts)? {
+                match axis {
+                    "X" => steps.x = value,
+                    "Y" => steps.y = value,
+                    "Z" => steps.z = value,
+                    _ => {},
+                }
+            }
+            report.counts = Some(steps);
+        }
+        Ok(report)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseEndStopStateError;
+impl FromStr for EndStopState {
+    type Err = ParseEndStopStateError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "open" => Ok(EndStopState::Open),
+            "triggered" => Ok(EndStopState::Triggered),
+            _ => Err(ParseEndStopStateError),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::jog::JogCommand;
 
     #[test]
     fn parse_xyz_zero_int() {
@@ -49,7 +136,7 @@ mod tests {
             assert_eq!(p.z, 0);
         }
         else {
-            assert!(false, "bad event parsed.")
+            panic!("bad event parsed.")
         }
     }
 
@@ -60,40 +147,117 @@ mod tests {
             assert_eq!(code, "G91");
         }
         else {
-            assert!(false, "bad event parsed.")
+            panic!("bad event parsed.")
         }
     }
 
     #[test]
     fn parse_sd_list_root() {
-        let state = "L:\n".parse().expect("parse success");
-        if let RemoteEvent::SDList(root) = state {
+        let state = "L:0 \n".parse().expect("parse success");
+        if let RemoteEvent::SDList((root, skip)) = state {
             assert_eq!(root, "");
+            assert_eq!(skip, 0);
         }
         else {
-            assert!(false, "bad event parsed.")
+            panic!("bad event parsed.")
         }
     }
 
     #[test]
     fn parse_sd_list_non_root() {
-        let state = "L:/D/directory\n".parse().expect("parse success");
-        if let RemoteEvent::SDList(root) = state {
+        let state = "L:5 /D/directory\n".parse().expect("parse success");
+        if let RemoteEvent::SDList((root, skip)) = state {
             assert_eq!(root, "/D/directory");
+            assert_eq!(skip, 5);
         }
         else {
-            assert!(false, "bad event parsed.")
+            panic!("bad event parsed.")
         }
     }
 
     #[test]
     fn parse_run_file() {
         let state = "F:/D/file.nc\n".parse().expect("parse success");
-        if let RemoteEvent::SDList(root) = state {
-            assert_eq!(root, "/D/file.nc");
+        if let RemoteEvent::SDLoadFile(path) = state {
+            assert_eq!(path, "/D/file.nc");
+        }
+        else {
+            panic!("bad event parsed.")
+        }
+    }
+
+    #[test]
+    fn parse_run_file_without_newline() {
+        let state = "F:/D/file.nc".parse().expect("parse success");
+        if let RemoteEvent::SDLoadFile(path) = state {
+            assert_eq!(path, "/D/file.nc");
         }
         else {
-            assert!(false, "bad event parsed.")
+            panic!("bad event parsed.")
         }
     }
+
+    #[test]
+    fn parse_resume_file() {
+        let state = "N:1200 /D/file.nc\n".parse().expect("parse success");
+        if let RemoteEvent::ResumeFile((path, line)) = state {
+            assert_eq!(path, "/D/file.nc");
+            assert_eq!(line, 1200);
+        }
+        else {
+            panic!("bad event parsed.")
+        }
+        assert!("N:/D/file.nc\n".parse::<RemoteEvent>().is_err());
+    }
+
+    #[test]
+    fn parse_job_commands() {
+        assert!(matches!("J:pause\n".parse(), Ok(RemoteEvent::JobPause)));
+        assert!(matches!("J:resume\r\n".parse(), Ok(RemoteEvent::JobResume)));
+        assert!(matches!("J:abort".parse(), Ok(RemoteEvent::JobAbort)));
+        assert!(matches!("J:start\n".parse(), Ok(RemoteEvent::JobStart)));
+        assert!("J:stop\n".parse::<RemoteEvent>().is_err());
+    }
+
+    #[test]
+    fn parse_jog_settings() {
+        assert!(matches!("D:step x 0.01\n".parse(), Ok(RemoteEvent::JogSetting(JogCommand::Step(Some('x'), 0.01)))));
+        assert!(matches!("D:?".parse(), Ok(RemoteEvent::JogSetting(JogCommand::Query))));
+        assert!("D:step x\n".parse::<RemoteEvent>().is_err());
+    }
+
+    #[test]
+    fn parse_noise() {
+        assert_eq!("\u{fffd}:x".parse::<RemoteEvent>().err(), Some(ParseRemoteEventError::BadStartingId));
+        assert_eq!("W\u{fffd}1".parse::<RemoteEvent>().err(), Some(ParseRemoteEventError::BadStartingId));
+        assert!(matches!("G:G0 X1 ; \u{fffd}".parse(), Ok(RemoteEvent::RunGCode(_))));
+    }
+
+    #[test]
+    fn parse_profile_commands() {
+        assert!(matches!("C:?\n".parse(), Ok(RemoteEvent::ListProfiles)));
+        assert!(matches!("C:engraver\r\n".parse(), Ok(RemoteEvent::SelectProfile(name)) if name == "engraver"));
+    }
+
+    #[test]
+    fn parse_m114_report() {
+        let report: PositionReport = "X:0.00 Y:127.00 Z:145.00 E:0.00 Count X: 0 Y:10160 Z:116000".parse().expect("parse success");
+        assert_eq!(report.position, Point3::new(0.0, 127.0, 145.0));
+        assert_eq!(report.extruder, Some(0.0));
+        assert_eq!(report.counts, Some(Point3::new(0, 10160, 116000)));
+    }
+
+    #[test]
+    fn parse_m114_report_without_counts() {
+        let report: PositionReport = "X:1.50 Y:-2.25 Z:3.00".parse().expect("parse success");
+        assert_eq!(report.position, Point3::new(1.5, -2.25, 3.0));
+        assert_eq!(report.extruder, None);
+        assert_eq!(report.counts, None);
+    }
+
+    #[test]
+    fn parse_m114_report_rejects_ok() {
+        assert!("ok".parse::<PositionReport>().is_err());
+        assert!("X:1.0 Y:2.0".parse::<PositionReport>().is_err());
+    }
 }
@@ -0,0 +1,171 @@
+use std::{collections::VecDeque, str::FromStr};
+
+/// XOR of every byte, as used by Marlin's `*` checksum.
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+/// Removes `;` and `( )` comments along with surrounding whitespace. The firmware
+/// drops comments before checking a line, so they can't be part of the checksum.
+pub fn strip_comments(line: &str) -> String {
+    let line = line.split(';').next().unwrap_or_default();
+    let mut stripped = String::with_capacity(line.len());
+    let mut in_comment = false;
+    for c in line.chars() {
+        match c {
+            '(' => in_comment = true,
+            ')' => in_comment = false,
+            c if !in_comment => stripped.push(c),
+            _ => {},
+        }
+    }
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// How much may be sent to the controller ahead of its acknowledgements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    /// At most this many lines waiting for an ok.
+    LineCounting(usize),
+    /// At most this many bytes, newlines included, sitting in the controller's
+    /// serial RX buffer. GRBL streams best this way with its 128 byte buffer.
+    CharacterCounting(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseFlowControlError;
+impl FromStr for FlowControl {
+    type Err = ParseFlowControlError;
+    /// `lines` or `chars`, with the size filled in later by `with_size`.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.trim().to_lowercase().as_str() {
+            "lines" => Ok(FlowControl::LineCounting(0)),
+            "chars" | "bytes" => Ok(FlowControl::CharacterCounting(0)),
+            _ => Err(ParseFlowControlError),
+        }
+    }
+}
+
+impl FlowControl {
+    pub fn with_size(self, size: usize) -> Self {
+        match self {
+            FlowControl::LineCounting(_) => FlowControl::LineCounting(size),
+            FlowControl::CharacterCounting(_) => FlowControl::CharacterCounting(size),
+        }
+    }
+
+    fn used(in_flight: &VecDeque<String>) -> usize {
+        in_flight.iter().map(|line| line.len() + 1).sum()
+    }
+
+    /// Whether anything more may be sent.
+    pub fn has_room(&self, in_flight: &VecDeque<String>) -> bool {
+        match self {
+            FlowControl::LineCounting(lines) => in_flight.len() < *lines,
+            FlowControl::CharacterCounting(bytes) => Self::used(in_flight) < *bytes,
+        }
+    }
+
+    /// Whether `next` fits alongside the lines still waiting for an ok. A line
+    /// larger than the whole buffer is let through once nothing else is in flight.
+    pub fn can_send(&self, in_flight: &VecDeque<String>, next: &str) -> bool {
+        match self {
+            FlowControl::LineCounting(lines) => in_flight.len() < *lines,
+            FlowControl::CharacterCounting(bytes) => {
+                let needed = next.len() + 1; // trailing newline
+                in_flight.is_empty() || Self::used(in_flight) + needed <= *bytes
+            },
+        }
+    }
+}
+
+/// Numbers and checksums outgoing lines (`N12 G1 X5*87`) and keeps the most
+/// recent ones so they can be sent again when the firmware asks for a resend.
+pub struct LineNumbering {
+    next: u32,
+    window: usize,
+    history: VecDeque<(u32, String)>,
+}
+
+impl LineNumbering {
+    pub fn new(window: usize) -> Self {
+        Self { next: 1, window, history: VecDeque::new() }
+    }
+
+    /// Returns the line to write for `code`. M110 resets the numbering and is sent as-is.
+    pub fn number(&mut self, code: &str) -> String {
+        let code = strip_comments(code);
+        if let Some(last) = code.strip_prefix("M110") {
+            let last = last.trim().trim_start_matches('N').parse().unwrap_or(0);
+            self.next = last + 1;
+            self.history.clear();
+            return code;
+        }
+        let numbered = format!("N{} {}", self.next, code);
+        let line = format!("{}*{}", numbered, checksum(&numbered));
+        self.history.push_back((self.next, line.clone()));
+        if self.history.len() > self.window {
+            self.history.pop_front();
+        }
+        self.next += 1;
+        line
+    }
+
+    /// Line number of a line written by `number`, None for lines sent as-is.
+    pub fn line_number(line: &str) -> Option<u32> {
+        line.strip_prefix('N')?.split(' ').next()?.parse().ok()
+    }
+
+    /// Already numbered lines from `line` onward. None if `line` has fallen out of the history window.
+    pub fn replay_from(&self, line: u32) -> Option<Vec<String>> {
+        let start = self.history.iter().position(|(n, _)| *n == line)?;
+        Some(self.history.iter().skip(start).map(|(_, numbered)| numbered.clone()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn character_counting() {
+        let flow = FlowControl::CharacterCounting(16);
+        let mut in_flight = VecDeque::new();
+        assert!(flow.can_send(&in_flight, "G1 X100 Y100 Z100 F500"));
+        in_flight.push_back("G0 X10".to_owned());
+        assert!(flow.can_send(&in_flight, "G0 Y10 Z"));
+        assert!(!flow.can_send(&in_flight, "G0 Y10 Z1"));
+        assert!(flow.has_room(&in_flight));
+    }
+
+    #[test]
+    fn line_counting() {
+        let flow: FlowControl = "lines".parse::<FlowControl>().expect("parse success").with_size(2);
+        let in_flight: VecDeque<_> = ["G0 X1".to_owned(), "G0 X2".to_owned()].into();
+        assert!(!flow.has_room(&in_flight));
+        assert!(!flow.can_send(&in_flight, "G0 X3"));
+    }
+
+    #[test]
+    fn numbers_and_checksums() {
+        let mut numbering = LineNumbering::new(8);
+        assert_eq!(numbering.number("M110 N0"), "M110 N0");
+        assert_eq!(numbering.number("G1 X10 ; cut"), "N1 G1 X10*80");
+        assert_eq!(numbering.number("G0 (rapid) Y5.5"), "N2 G0 Y5.5*124");
+    }
+
+    #[test]
+    fn replay_within_window() {
+        let mut numbering = LineNumbering::new(2);
+        for code in ["G0 X1", "G0 X2", "G0 X3"] {
+            numbering.number(code);
+        }
+        assert!(numbering.replay_from(1).is_none());
+        let replay = numbering.replay_from(2).expect("line 2 in history");
+        assert_eq!(replay.len(), 2);
+        assert!(replay[0].starts_with("N2 G0 X2*"));
+        assert!(replay[1].starts_with("N3 G0 X3*"));
+        assert_eq!(LineNumbering::line_number(&replay[1]), Some(3));
+        assert_eq!(LineNumbering::line_number("M110 N0"), None);
+    }
+}
@@ -0,0 +1,70 @@
+use std::{fs::read_to_string, path::PathBuf, str::FromStr};
+
+/// G-code run before or after a job. Configured either inline with lines split
+/// by `|`, or as `@/path/to/file.nc` which is read each time the job runs so
+/// edits on disk take effect without a restart.
+///
+/// `{name}` in the text is replaced with the variable of that name, see `render`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Template {
+    Inline(Vec<String>),
+    File(PathBuf),
+}
+
+impl Default for Template {
+    fn default() -> Self { Template::Inline(vec![]) }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseTemplateError;
+impl FromStr for Template {
+    type Err = ParseTemplateError;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        if let Some(path) = input.strip_prefix('@') {
+            if path.is_empty() {
+                return Err(ParseTemplateError);
+            }
+            return Ok(Template::File(PathBuf::from(path)));
+        }
+        Ok(Template::Inline(
+            input.split(['|', '\n']).map(str::trim).filter(|l| !l.is_empty()).map(str::to_owned).collect()
+        ))
+    }
+}
+
+impl Template {
+    fn lines(&self) -> std::io::Result<Vec<String>> {
+        match self {
+            Template::Inline(lines) => Ok(lines.clone()),
+            Template::File(path) => Ok(read_to_string(path)?.lines().map(str::to_owned).collect()),
+        }
+    }
+
+    /// The template's lines with each `{name}` replaced by its value. Unknown
+    /// names are left as they are.
+    pub fn render(&self, vars: &[(&str, String)]) -> std::io::Result<Vec<String>> {
+        Ok(self.lines()?.into_iter().map(|line| {
+            vars.iter().fold(line, |line, (name, value)| line.replace(&format!("{{{name}}}"), value))
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_with_variables() {
+        let template: Template = "G90 | {wcs} |M3 S{rpm} ; {file_name}|".parse().expect("parse success");
+        let lines = template.render(&[("wcs", "G55".to_owned()), ("file_name", "part.nc".to_owned())]).expect("render");
+        assert_eq!(lines, ["G90", "G55", "M3 S{rpm} ; part.nc"]);
+    }
+
+    #[test]
+    fn file_template() {
+        assert_eq!("@/etc/cnc/start.nc".parse(), Ok(Template::File(PathBuf::from("/etc/cnc/start.nc"))));
+        assert!("@".parse::<Template>().is_err());
+        assert!(Template::File(PathBuf::from("/nonexistent/start.nc")).render(&[]).is_err());
+    }
+}